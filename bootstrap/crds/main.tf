@@ -43,13 +43,13 @@ resource "kubernetes_manifest" "customresourcedefinition_kupoports_demeter_run"
               "type"     = "string"
             },
             {
-              "jsonPath" = ".status.authenticatedEndpointUrl"
-              "name"     = "Authenticated Endpoint URL"
+              "jsonPath" = ".status.authTokenSecret"
+              "name"     = "Auth Token Secret"
               "type"     = "string"
             },
             {
-              "jsonPath" = ".status.authToken"
-              "name"     = "Auth Token"
+              "jsonPath" = ".status.authTokenFingerprint"
+              "name"     = "Auth Token Fingerprint"
               "type"     = "string"
             },
           ]
@@ -60,10 +60,163 @@ resource "kubernetes_manifest" "customresourcedefinition_kupoports_demeter_run"
               "properties" = {
                 "spec" = {
                   "properties" = {
+                    "allowedEndpoints" = {
+                      "default"     = []
+                      "description" = "Routes every key of the port is restricted to, all routes when empty."
+                      "items" = {
+                        "properties" = {
+                          "method" = {
+                            "description" = "HTTP method of the route, any method when not set."
+                            "nullable"    = true
+                            "type"        = "string"
+                          }
+                          "path" = {
+                            "description" = "Regular expression matched against the request path."
+                            "type"        = "string"
+                          }
+                        }
+                        "required" = [
+                          "path",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
+                    "allowedIps" = {
+                      "default"     = []
+                      "description" = "Client networks in CIDR notation allowed to use the port keys, any address when empty."
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "type" = "array"
+                    }
+                    "allowedOrigins" = {
+                      "default"     = []
+                      "description" = "Browser origins allowed to use the port keys, any origin when empty."
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "type" = "array"
+                    }
+                    "apiKeys" = {
+                      "default"     = []
+                      "description" = "Extra keys of the port, each one can be revoked on its own."
+                      "items" = {
+                        "properties" = {
+                          "allowedEndpoints" = {
+                            "default"     = []
+                            "description" = "Routes the key is restricted to, on top of the port ones."
+                            "items" = {
+                              "properties" = {
+                                "method" = {
+                                  "description" = "HTTP method of the route, any method when not set."
+                                  "nullable"    = true
+                                  "type"        = "string"
+                                }
+                                "path" = {
+                                  "description" = "Regular expression matched against the request path."
+                                  "type"        = "string"
+                                }
+                              }
+                              "required" = [
+                                "path",
+                              ]
+                              "type" = "object"
+                            }
+                            "type" = "array"
+                          }
+                          "deniedEndpoints" = {
+                            "default"     = []
+                            "description" = "Routes the key can't request, on top of the port ones."
+                            "items" = {
+                              "properties" = {
+                                "method" = {
+                                  "description" = "HTTP method of the route, any method when not set."
+                                  "nullable"    = true
+                                  "type"        = "string"
+                                }
+                                "path" = {
+                                  "description" = "Regular expression matched against the request path."
+                                  "type"        = "string"
+                                }
+                              }
+                              "required" = [
+                                "path",
+                              ]
+                              "type" = "object"
+                            }
+                            "type" = "array"
+                          }
+                          "name" = {
+                            "description" = "Unique in the port, used as the key label in the proxy metrics."
+                            "type"        = "string"
+                          }
+                          "secretRef" = {
+                            "description" = "Secret in the port namespace holding the key, it's generated when not set."
+                            "nullable"    = true
+                            "properties" = {
+                              "key" = {
+                                "type" = "string"
+                              }
+                              "name" = {
+                                "type" = "string"
+                              }
+                            }
+                            "required" = [
+                              "key",
+                              "name",
+                            ]
+                            "type" = "object"
+                          }
+                        }
+                        "required" = [
+                          "name",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
                     "authToken" = {
                       "nullable" = true
                       "type"     = "string"
                     }
+                    "deniedEndpoints" = {
+                      "default"     = []
+                      "description" = "Routes no key of the port can request."
+                      "items" = {
+                        "properties" = {
+                          "method" = {
+                            "description" = "HTTP method of the route, any method when not set."
+                            "nullable"    = true
+                            "type"        = "string"
+                          }
+                          "path" = {
+                            "description" = "Regular expression matched against the request path."
+                            "type"        = "string"
+                          }
+                        }
+                        "required" = [
+                          "path",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
+                    "deniedIps" = {
+                      "default"     = []
+                      "description" = "Client networks in CIDR notation that can't use the port keys."
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "type" = "array"
+                    }
+                    "keyGeneration" = {
+                      "description" = "Bumping it derives a new key, the previous one is kept valid during a grace period."
+                      "format"      = "uint32"
+                      "minimum"     = 0
+                      "nullable"    = true
+                      "type"        = "integer"
+                    }
                     "kupoVersion" = {
                       "nullable" = true
                       "type"     = "string"
@@ -91,19 +244,122 @@ resource "kubernetes_manifest" "customresourcedefinition_kupoports_demeter_run"
                 "status" = {
                   "nullable" = true
                   "properties" = {
+                    "apiKeys" = {
+                      "default" = []
+                      "items" = {
+                        "properties" = {
+                          "fingerprint" = {
+                            "type" = "string"
+                          }
+                          "name" = {
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "fingerprint",
+                          "name",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
                     "authToken" = {
-                      "type" = "string"
+                      "description" = "Main key and endpoint of the port, still published for proxies that don't read the Secret yet. To be dropped once every proxy reads Secrets."
+                      "nullable"    = true
+                      "type"        = "string"
+                    }
+                    "authTokenFingerprint" = {
+                      "nullable" = true
+                      "type"     = "string"
+                    }
+                    "authTokenHash" = {
+                      "nullable" = true
+                      "type"     = "string"
+                    }
+                    "authTokenSecret" = {
+                      "description" = "Secret holding the port keys and the authenticated endpoint urls."
+                      "nullable"    = true
+                      "type"        = "string"
                     }
                     "authenticatedEndpointUrl" = {
                       "nullable" = true
                       "type"     = "string"
                     }
+                    "conditions" = {
+                      "default" = []
+                      "items" = {
+                        "description" = "Condition of the port, `ApiKeysValid` is false while `spec.apiKeys` can't be reconciled."
+                        "properties" = {
+                          "lastTransitionTime" = {
+                            "description" = "RFC 3339 timestamp of the last change of `status`."
+                            "type"        = "string"
+                          }
+                          "message" = {
+                            "type" = "string"
+                          }
+                          "reason" = {
+                            "type" = "string"
+                          }
+                          "status" = {
+                            "type" = "string"
+                          }
+                          "type" = {
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "lastTransitionTime",
+                          "message",
+                          "reason",
+                          "status",
+                          "type",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
                     "endpointUrl" = {
                       "type" = "string"
                     }
+                    "endpointUrls" = {
+                      "default"     = []
+                      "description" = "Endpoint urls in every zone the port is served from, starting with `endpointUrl`."
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "type" = "array"
+                    }
+                    "previousAuthTokens" = {
+                      "default" = []
+                      "items" = {
+                        "properties" = {
+                          "authToken" = {
+                            "description" = "Key published by versions that kept keys in the status, only read to move it to the Secret and never written back."
+                            "nullable"    = true
+                            "type"        = "string"
+                          }
+                          "expiresAt" = {
+                            "description" = "RFC 3339 timestamp after which the key is no longer accepted."
+                            "type"        = "string"
+                          }
+                          "fingerprint" = {
+                            "default" = ""
+                            "type"    = "string"
+                          }
+                          "name" = {
+                            "default" = "default"
+                            "type"    = "string"
+                          }
+                        }
+                        "required" = [
+                          "expiresAt",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
                   }
                   "required" = [
-                    "authToken",
                     "endpointUrl",
                   ]
                   "type" = "object"
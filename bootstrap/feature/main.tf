@@ -26,6 +26,11 @@ variable "dns_zone" {
   default = "demeter.run"
 }
 
+variable "extra_zones" {
+  type    = list(string)
+  default = []
+}
+
 output "namespace" {
   value = var.namespace
 }
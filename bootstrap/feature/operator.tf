@@ -75,6 +75,11 @@ resource "kubernetes_deployment_v1" "operator" {
             value = var.dns_zone
           }
 
+          env {
+            name  = "EXTRA_ZONES"
+            value = join(",", var.extra_zones)
+          }
+
           resources {
             limits = {
               cpu    = var.resources.limits.cpu
@@ -11,6 +11,7 @@ module "kupo_feature" {
   ingress_class       = var.ingress_class
   extension_subdomain = var.extension_subdomain
   dns_zone            = var.dns_zone
+  extra_zones         = var.extra_zones
   api_key_salt        = var.api_key_salt
   namespace           = var.namespace
   resources           = var.operator_resources
@@ -63,6 +64,8 @@ module "kupo_proxies_blue" {
   cert_secret_name  = "proxy-blue-wildcard-tls"
   kupo_instances    = var.proxy_blue_instance_per_network
   dns_names         = var.dns_names
+  extra_dns_names   = var.extra_dns_names
+  limiter_redis_url = var.proxy_limiter_redis_url
 }
 
 module "kupo_proxies_green" {
@@ -83,6 +86,8 @@ module "kupo_proxies_green" {
   cert_secret_name  = "proxy-green-wildcard-tls"
   kupo_instances    = var.proxy_green_instance_per_network
   dns_names         = var.dns_names
+  extra_dns_names   = var.extra_dns_names
+  limiter_redis_url = var.proxy_limiter_redis_url
 }
 
 module "kupo_cells" {
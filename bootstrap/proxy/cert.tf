@@ -8,6 +8,7 @@ locals {
       : "${var.extension_name}-proxy-wildcard-tls"
     )
   )
+  extra_cert_secret_name = "${local.cert_secret_name}-extra"
 }
 
 resource "kubernetes_manifest" "certificate_cluster_wildcard_tls" {
@@ -29,3 +30,26 @@ resource "kubernetes_manifest" "certificate_cluster_wildcard_tls" {
     }
   }
 }
+
+// Served by SNI next to the main certificate, eg: while migrating to another DNS zone.
+resource "kubernetes_manifest" "certificate_cluster_extra_tls" {
+  count = length(var.extra_dns_names) > 0 ? 1 : 0
+
+  manifest = {
+    "apiVersion" = "cert-manager.io/v1"
+    "kind"       = "Certificate"
+    "metadata" = {
+      "name"      = local.extra_cert_secret_name
+      "namespace" = var.namespace
+    }
+    "spec" = {
+      "dnsNames" = var.extra_dns_names
+
+      "issuerRef" = {
+        "kind" = "ClusterIssuer"
+        "name" = var.cluster_issuer
+      }
+      "secretName" = local.extra_cert_secret_name
+    }
+  }
+}
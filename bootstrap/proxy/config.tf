@@ -1,6 +1,8 @@
-// numbers here should consider number of proxy replicas
+// numbers here should consider number of proxy replicas, limits and quotas are global when the
+// replicas share their counters through the limiter store
 locals {
   config_map_name = var.environment != null ? "${var.environment}-proxy-config" : "proxy-config"
+  limit_replicas  = var.limiter_redis_url != null ? 1 : var.replicas
 
   tiers = [
     {
@@ -8,11 +10,11 @@ locals {
       "rates" = [
         {
           "interval" = "1m",
-          "limit"    = floor(5 * 60 / var.replicas)
+          "limit"    = floor(5 * 60 / local.limit_replicas)
         },
         {
           "interval" = "1d",
-          "limit"    = floor(430000 / var.replicas)
+          "limit"    = floor(430000 / local.limit_replicas)
         }
       ]
     },
@@ -21,11 +23,11 @@ locals {
       "rates" = [
         {
           "interval" = "1m",
-          "limit"    = floor(20 * 60 / var.replicas)
+          "limit"    = floor(20 * 60 / local.limit_replicas)
         },
         {
           "interval" = "1d",
-          "limit"    = floor(1700000 / var.replicas)
+          "limit"    = floor(1700000 / local.limit_replicas)
         }
       ]
     },
@@ -34,11 +36,11 @@ locals {
       "rates" = [
         {
           "interval" = "1m",
-          "limit"    = floor(100 * 60 / var.replicas)
+          "limit"    = floor(100 * 60 / local.limit_replicas)
         },
         {
           "interval" = "1d",
-          "limit"    = floor(8600000 / var.replicas)
+          "limit"    = floor(8600000 / local.limit_replicas)
         }
       ]
     },
@@ -47,11 +49,11 @@ locals {
       "rates" = [
         {
           "interval" = "1m",
-          "limit"    = floor(300 * 60 / var.replicas)
+          "limit"    = floor(300 * 60 / local.limit_replicas)
         },
         {
           "interval" = "1d",
-          "limit"    = floor(26000000 / var.replicas)
+          "limit"    = floor(26000000 / local.limit_replicas)
         }
       ]
     }
@@ -89,11 +89,29 @@ resource "kubernetes_deployment_v1" "kupo_proxy" {
             value = "/certs/tls.key"
           }
 
+          dynamic "env" {
+            for_each = length(var.extra_dns_names) > 0 ? [1] : []
+            content {
+              name = "SSL_CERTIFICATES"
+              value = jsonencode([
+                { crt_path = "/certs-extra/tls.crt", key_path = "/certs-extra/tls.key" }
+              ])
+            }
+          }
+
           env {
             name  = "PROXY_TIERS_PATH"
             value = "/configs/tiers.toml"
           }
 
+          dynamic "env" {
+            for_each = var.limiter_redis_url != null ? [var.limiter_redis_url] : []
+            content {
+              name  = "LIMITER_REDIS_URL"
+              value = env.value
+            }
+          }
+
           env {
             name  = "CORS_ALLOW_ORIGIN"
             value = "*"
@@ -119,6 +137,14 @@ resource "kubernetes_deployment_v1" "kupo_proxy" {
             name       = "certs"
           }
 
+          dynamic "volume_mount" {
+            for_each = length(var.extra_dns_names) > 0 ? [1] : []
+            content {
+              mount_path = "/certs-extra"
+              name       = "certs-extra"
+            }
+          }
+
           volume_mount {
             mount_path = "/configs"
             name       = "configs"
@@ -132,6 +158,16 @@ resource "kubernetes_deployment_v1" "kupo_proxy" {
           }
         }
 
+        dynamic "volume" {
+          for_each = length(var.extra_dns_names) > 0 ? [1] : []
+          content {
+            name = "certs-extra"
+            secret {
+              secret_name = local.extra_cert_secret_name
+            }
+          }
+        }
+
         volume {
           name = "configs"
           config_map {
@@ -75,6 +75,18 @@ variable "dns_names" {
   type        = list(string)
 }
 
+variable "limiter_redis_url" {
+  description = "Redis-protocol store shared by the replicas for rate limits and quota usage"
+  type        = string
+  default     = null
+}
+
+variable "extra_dns_names" {
+  description = "DNS names of a second TLS certificate, picked by SNI next to the one of dns_names"
+  type        = list(string)
+  default     = []
+}
+
 variable "cloud_provider" {
   type    = string
   default = "aws"
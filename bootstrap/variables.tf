@@ -72,11 +72,29 @@ variable "dns_zone" {
   default = "demeter.run"
 }
 
+variable "extra_zones" {
+  description = "Zones as subdomain.zone where ports are also published, eg: while migrating DNS zones"
+  type        = list(string)
+  default     = []
+}
+
 variable "dns_names" {
   description = "List of DNS names to create certificate"
   type        = list(string)
 }
 
+variable "proxy_limiter_redis_url" {
+  description = "Redis-protocol store shared by the proxy replicas, so rate limits and quotas are global and survive restarts"
+  type        = string
+  default     = null
+}
+
+variable "extra_dns_names" {
+  description = "List of DNS names of a second certificate served by SNI, eg: a new DNS zone"
+  type        = list(string)
+  default     = []
+}
+
 // Proxies
 variable "proxy_green_instance_per_network" {
   description = "Map of network to kupo instance for proxy green"
@@ -0,0 +1,273 @@
+use chrono::Utc;
+use k8s_openapi::{api::core::v1::Secret, ByteString};
+use kube::{
+    api::{ObjectMeta, Patch, PatchParams},
+    Api, Client, Resource, ResourceExt,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::{
+    build_api_key_fingerprint, get_config, parse_expiration, KupoPort, KupoPortApiKeyStatus,
+    KupoPortPreviousKey,
+};
+
+/// Label set on the key Secrets, its value is the name of the port owning the Secret.
+pub static AUTH_SECRET_LABEL: &str = "demeter.run/kupo-port";
+
+/// Name of the port main key, the one in `spec.authToken` or generated for every port.
+pub static DEFAULT_API_KEY_NAME: &str = "default";
+
+static AUTH_TOKEN_FIELD: &str = "authToken";
+static AUTHENTICATED_ENDPOINT_URL_FIELD: &str = "authenticatedEndpointUrl";
+static AUTHENTICATED_ENDPOINT_URLS_FIELD: &str = "authenticatedEndpointUrls";
+static API_KEYS_FIELD: &str = "apiKeys";
+static PREVIOUS_AUTH_TOKENS_FIELD: &str = "previousAuthTokens";
+
+/// Keys of a port, kept in a Secret owned by the port instead of the port status.
+#[derive(Clone, Default, Debug)]
+pub struct KupoPortKeys {
+    pub auth_token: String,
+    pub authenticated_endpoint_url: Option<String>,
+    /// Authenticated endpoint urls in every zone, starting with the main one.
+    pub authenticated_endpoint_urls: Vec<String>,
+    pub api_keys: Vec<NamedApiKey>,
+    pub previous_auth_tokens: Vec<PreviousApiKey>,
+}
+
+/// Extra key of a port from `spec.apiKeys`.
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NamedApiKey {
+    pub name: String,
+    pub auth_token: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PreviousApiKey {
+    #[serde(default = "default_api_key_name")]
+    pub name: String,
+    pub auth_token: String,
+    /// RFC 3339 timestamp after which the key is no longer accepted.
+    pub expires_at: String,
+}
+
+impl KupoPortKeys {
+    pub fn from_secret(secret: &Secret) -> Option<Self> {
+        let data = secret.data.as_ref()?;
+        let field = |name: &str| {
+            data.get(name)
+                .and_then(|value| String::from_utf8(value.0.clone()).ok())
+        };
+
+        let auth_token = field(AUTH_TOKEN_FIELD)?;
+        let authenticated_endpoint_url = field(AUTHENTICATED_ENDPOINT_URL_FIELD);
+        let authenticated_endpoint_urls = field(AUTHENTICATED_ENDPOINT_URLS_FIELD)
+            .and_then(|value| serde_json::from_str(&value).ok())
+            .unwrap_or_default();
+        let api_keys = field(API_KEYS_FIELD)
+            .and_then(|value| serde_json::from_str(&value).ok())
+            .unwrap_or_default();
+        let previous_auth_tokens = field(PREVIOUS_AUTH_TOKENS_FIELD)
+            .and_then(|value| serde_json::from_str(&value).ok())
+            .unwrap_or_default();
+
+        Some(Self {
+            auth_token,
+            authenticated_endpoint_url,
+            authenticated_endpoint_urls,
+            api_keys,
+            previous_auth_tokens,
+        })
+    }
+
+    pub fn to_secret(&self, crd: &KupoPort) -> Secret {
+        let mut data = BTreeMap::new();
+        data.insert(
+            AUTH_TOKEN_FIELD.to_string(),
+            ByteString(self.auth_token.clone().into_bytes()),
+        );
+        if let Some(url) = &self.authenticated_endpoint_url {
+            data.insert(
+                AUTHENTICATED_ENDPOINT_URL_FIELD.to_string(),
+                ByteString(url.clone().into_bytes()),
+            );
+        }
+        data.insert(
+            AUTHENTICATED_ENDPOINT_URLS_FIELD.to_string(),
+            ByteString(serde_json::to_vec(&self.authenticated_endpoint_urls).unwrap_or_default()),
+        );
+        data.insert(
+            API_KEYS_FIELD.to_string(),
+            ByteString(serde_json::to_vec(&self.api_keys).unwrap_or_default()),
+        );
+        data.insert(
+            PREVIOUS_AUTH_TOKENS_FIELD.to_string(),
+            ByteString(serde_json::to_vec(&self.previous_auth_tokens).unwrap_or_default()),
+        );
+
+        Secret {
+            metadata: ObjectMeta {
+                name: Some(build_auth_secret_name(&crd.name_any())),
+                namespace: crd.namespace(),
+                labels: Some(BTreeMap::from([(
+                    AUTH_SECRET_LABEL.to_string(),
+                    crd.name_any(),
+                )])),
+                owner_references: crd.controller_owner_ref(&()).map(|owner| vec![owner]),
+                ..Default::default()
+            },
+            data: Some(data),
+            type_: Some("Opaque".into()),
+            ..Default::default()
+        }
+    }
+
+    /// Name and value of every current key, starting with the default one.
+    pub fn keys(&self) -> Vec<(&str, &str)> {
+        let mut keys = vec![(DEFAULT_API_KEY_NAME, self.auth_token.as_str())];
+        keys.extend(
+            self.api_keys
+                .iter()
+                .map(|key| (key.name.as_str(), key.auth_token.as_str())),
+        );
+        keys
+    }
+
+    /// Extra keys as published in the port status, without the keys themselves.
+    pub fn api_key_fingerprints(&self) -> Vec<KupoPortApiKeyStatus> {
+        self.api_keys
+            .iter()
+            .map(|key| KupoPortApiKeyStatus {
+                name: key.name.clone(),
+                fingerprint: build_api_key_fingerprint(&key.auth_token),
+            })
+            .collect()
+    }
+
+    /// Previous keys as published in the port status, without the keys themselves.
+    pub fn previous_fingerprints(&self) -> Vec<KupoPortPreviousKey> {
+        self.previous_auth_tokens
+            .iter()
+            .map(|previous| KupoPortPreviousKey {
+                name: previous.name.clone(),
+                fingerprint: build_api_key_fingerprint(&previous.auth_token),
+                expires_at: previous.expires_at.clone(),
+                auth_token: None,
+            })
+            .collect()
+    }
+}
+
+pub fn build_auth_secret_name(port_name: &str) -> String {
+    format!("kupo-auth-{port_name}")
+}
+
+pub(crate) fn default_api_key_name() -> String {
+    DEFAULT_API_KEY_NAME.to_string()
+}
+
+/// Previous keys of ports reconciled before the keys moved to a Secret, read from the legacy
+/// status so a rotation in progress keeps its grace period through the upgrade.
+pub fn legacy_port_keys(crd: &KupoPort) -> Option<KupoPortKeys> {
+    let previous_auth_tokens: Vec<PreviousApiKey> = crd
+        .status
+        .as_ref()?
+        .previous_auth_tokens
+        .iter()
+        .filter_map(|previous| {
+            Some(PreviousApiKey {
+                name: previous.name.clone(),
+                auth_token: previous.auth_token.clone()?,
+                expires_at: previous.expires_at.clone(),
+            })
+        })
+        .collect();
+
+    if previous_auth_tokens.is_empty() {
+        return None;
+    }
+
+    Some(KupoPortKeys {
+        previous_auth_tokens,
+        ..Default::default()
+    })
+}
+
+/// Keys replaced by a rotation stay valid for `API_KEY_ROTATION_GRACE`. Returns the keys still
+/// in their grace period, plus the stored keys whose value changed. Keys removed from the port
+/// are revoked right away.
+pub fn build_previous_api_keys(
+    current: Option<&KupoPortKeys>,
+    next: &KupoPortKeys,
+) -> Vec<PreviousApiKey> {
+    let Some(current) = current else {
+        return vec![];
+    };
+
+    let next_keys: BTreeMap<&str, &str> = next.keys().into_iter().collect();
+    let replaced = |name: &str, auth_token: &str| {
+        next_keys
+            .get(name)
+            .is_some_and(|next_token| *next_token != auth_token)
+    };
+
+    let now = Utc::now();
+    let mut previous_keys: Vec<PreviousApiKey> = current
+        .previous_auth_tokens
+        .iter()
+        .filter(|previous| replaced(&previous.name, &previous.auth_token))
+        .filter(|previous| parse_expiration(&previous.expires_at).is_some_and(|e| e > now))
+        .cloned()
+        .collect();
+
+    let grace = get_config().api_key_rotation_grace;
+    let expires_at = now + chrono::Duration::from_std(grace).unwrap_or_default();
+    for (name, auth_token) in current.keys() {
+        if !auth_token.is_empty() && replaced(name, auth_token) {
+            previous_keys.push(PreviousApiKey {
+                name: name.to_string(),
+                auth_token: auth_token.to_string(),
+                expires_at: expires_at.to_rfc3339(),
+            });
+        }
+    }
+
+    previous_keys
+}
+
+pub async fn get_auth_secret(
+    client: Client,
+    namespace: &str,
+    port_name: &str,
+) -> Result<Option<Secret>, kube::Error> {
+    let api: Api<Secret> = Api::namespaced(client, namespace);
+    api.get_opt(&build_auth_secret_name(port_name)).await
+}
+
+pub async fn apply_auth_secret(client: Client, secret: &Secret) -> Result<(), kube::Error> {
+    let namespace = secret.namespace().unwrap_or_default();
+    let api: Api<Secret> = Api::namespaced(client, &namespace);
+
+    let patch_params = PatchParams::apply("kupo-operator").force();
+    api.patch(&secret.name_any(), &patch_params, &Patch::Apply(secret))
+        .await?;
+    Ok(())
+}
+
+/// Value of a key of a Secret the operator doesn't own, used by keys referenced from Secrets.
+pub async fn get_secret_value(
+    client: Client,
+    namespace: &str,
+    name: &str,
+    key: &str,
+) -> Result<Option<String>, kube::Error> {
+    let api: Api<Secret> = Api::namespaced(client, namespace);
+    let secret = api.get_opt(name).await?;
+
+    Ok(secret
+        .and_then(|secret| secret.data)
+        .and_then(|data| data.get(key).cloned())
+        .and_then(|value| String::from_utf8(value.0).ok()))
+}
@@ -13,10 +13,12 @@ pub fn get_config() -> &'static Config {
 pub struct Config {
     pub dns_zone: String,
     pub extension_subdomain: String,
+    pub extra_zones: Vec<String>,
     pub api_key_salt: String,
     pub metrics_delay: Duration,
     pub prometheus_url: String,
     pub default_kupo_version: String,
+    pub api_key_rotation_grace: Duration,
 }
 
 impl Config {
@@ -24,6 +26,14 @@ impl Config {
         Self {
             dns_zone: env::var("DNS_ZONE").unwrap_or("demeter.run".into()),
             extension_subdomain: env::var("EXTENSION_SUBDOMAIN").unwrap_or("kupo-m1".into()),
+            extra_zones: env::var("EXTRA_ZONES")
+                .map(|v| {
+                    v.split(',')
+                        .map(|zone| zone.trim().to_string())
+                        .filter(|zone| !zone.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
             api_key_salt: env::var("API_KEY_SALT").unwrap_or("kupo-salt".into()),
             metrics_delay: Duration::from_secs(
                 std::env::var("METRICS_DELAY")
@@ -33,6 +43,14 @@ impl Config {
             ),
             prometheus_url: env::var("PROMETHEUS_URL").expect("PROMETHEUS_URL must be set"),
             default_kupo_version: env::var("DEFAULT_KUPO_VERSION").unwrap_or("v2".into()),
+            api_key_rotation_grace: Duration::from_secs(
+                env::var("API_KEY_ROTATION_GRACE")
+                    .map(|v| {
+                        v.parse::<u64>()
+                            .expect("API_KEY_ROTATION_GRACE must be a number in seconds")
+                    })
+                    .unwrap_or(86400),
+            ),
         }
     }
 }
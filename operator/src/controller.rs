@@ -1,17 +1,27 @@
+use chrono::Utc;
 use futures::StreamExt;
+use k8s_openapi::api::core::v1::Secret;
 use kube::{
     runtime::{controller::Action, watcher::Config as WatcherConfig, Controller},
     Api, Client, CustomResource, CustomResourceExt, ResourceExt,
 };
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, time::Duration};
-use tracing::{error, info, instrument};
+use std::{collections::BTreeSet, sync::Arc, time::Duration};
+use tracing::{error, info, instrument, warn};
 
-use crate::{build_api_key, build_hostname, patch_resource_status, Error, Metrics, Result, State};
+use crate::{
+    apply_auth_secret, build_api_key, build_api_key_fingerprint, build_api_key_hash,
+    build_hostnames, build_named_api_key, build_previous_api_keys, default_api_key_name,
+    get_auth_secret, get_secret_value, legacy_port_keys, next_api_key_expiration,
+    patch_resource_status, Error, KupoPortKeys, Metrics, NamedApiKey, Result, State,
+    AUTH_SECRET_LABEL, DEFAULT_API_KEY_NAME,
+};
 
 pub static KUPO_PORT_FINALIZER: &str = "kupoports.demeter.run";
 
+static SECRET_REF_RESYNC_INTERVAL: Duration = Duration::from_secs(300);
+
 struct Context {
     pub client: Client,
     pub metrics: Metrics,
@@ -37,8 +47,8 @@ impl Context {
         {"name": "Pruned", "jsonPath": ".spec.pruneUtxo", "type": "boolean"},
         {"name": "Throughput Tier", "jsonPath":".spec.throughputTier", "type": "string"}, 
         {"name": "Endpoint URL", "jsonPath": ".status.endpointUrl", "type": "string"},
-        {"name": "Authenticated Endpoint URL", "jsonPath": ".status.authenticatedEndpointUrl", "type": "string"},
-        {"name": "Auth Token", "jsonPath": ".status.authToken", "type": "string"}
+        {"name": "Auth Token Secret", "jsonPath": ".status.authTokenSecret", "type": "string"},
+        {"name": "Auth Token Fingerprint", "jsonPath": ".status.authTokenFingerprint", "type": "string"}
     "#)]
 #[serde(rename_all = "camelCase")]
 pub struct KupoPortSpec {
@@ -48,31 +58,258 @@ pub struct KupoPortSpec {
     pub throughput_tier: String,
     pub kupo_version: Option<String>,
     pub auth_token: Option<String>,
+    /// Bumping it derives a new key, the previous one is kept valid during a grace period.
+    pub key_generation: Option<u32>,
+    /// Extra keys of the port, each one can be revoked on its own.
+    #[serde(default)]
+    pub api_keys: Vec<KupoPortApiKey>,
+    /// Routes every key of the port is restricted to, all routes when empty.
+    #[serde(default)]
+    pub allowed_endpoints: Vec<KupoPortEndpoint>,
+    /// Routes no key of the port can request.
+    #[serde(default)]
+    pub denied_endpoints: Vec<KupoPortEndpoint>,
+    /// Browser origins allowed to use the port keys, any origin when empty.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// Client networks in CIDR notation allowed to use the port keys, any address when empty.
+    #[serde(default)]
+    pub allowed_ips: Vec<String>,
+    /// Client networks in CIDR notation that can't use the port keys.
+    #[serde(default)]
+    pub denied_ips: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct KupoPortApiKey {
+    /// Unique in the port, used as the key label in the proxy metrics.
+    pub name: String,
+    /// Secret in the port namespace holding the key, it's generated when not set.
+    pub secret_ref: Option<KupoPortApiKeySecretRef>,
+    /// Routes the key is restricted to, on top of the port ones.
+    #[serde(default)]
+    pub allowed_endpoints: Vec<KupoPortEndpoint>,
+    /// Routes the key can't request, on top of the port ones.
+    #[serde(default)]
+    pub denied_endpoints: Vec<KupoPortEndpoint>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct KupoPortEndpoint {
+    /// HTTP method of the route, any method when not set.
+    pub method: Option<String>,
+    /// Regular expression matched against the request path.
+    pub path: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct KupoPortApiKeySecretRef {
+    pub name: String,
+    pub key: String,
 }
 
 #[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct KupoPortStatus {
     pub endpoint_url: String,
+    /// Endpoint urls in every zone the port is served from, starting with `endpointUrl`.
+    #[serde(default)]
+    pub endpoint_urls: Vec<String>,
+    /// Secret holding the port keys and the authenticated endpoint urls.
+    pub auth_token_secret: Option<String>,
+    pub auth_token_fingerprint: Option<String>,
+    /// Main key and endpoint of the port, still published for proxies that don't read the
+    /// Secret yet. To be dropped once every proxy reads Secrets.
+    pub auth_token: Option<String>,
+    pub auth_token_hash: Option<String>,
     pub authenticated_endpoint_url: Option<String>,
-    pub auth_token: String,
+    #[serde(default)]
+    pub api_keys: Vec<KupoPortApiKeyStatus>,
+    #[serde(default)]
+    pub previous_auth_tokens: Vec<KupoPortPreviousKey>,
+    #[serde(default)]
+    pub conditions: Vec<KupoPortCondition>,
+}
+
+/// Condition of the port, `ApiKeysValid` is false while `spec.apiKeys` can't be reconciled.
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct KupoPortCondition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub status: String,
+    pub reason: String,
+    pub message: String,
+    /// RFC 3339 timestamp of the last change of `status`.
+    pub last_transition_time: String,
+}
+
+static API_KEYS_VALID_CONDITION: &str = "ApiKeysValid";
+
+/// Keeps the transition time of the current condition when its status didn't change.
+fn build_api_keys_condition(
+    crd: &KupoPort,
+    valid: bool,
+    reason: &str,
+    message: &str,
+) -> KupoPortCondition {
+    let status = if valid { "True" } else { "False" };
+    let last_transition_time = crd
+        .status
+        .as_ref()
+        .and_then(|status| {
+            status
+                .conditions
+                .iter()
+                .find(|condition| condition.type_ == API_KEYS_VALID_CONDITION)
+        })
+        .filter(|condition| condition.status == status)
+        .map(|condition| condition.last_transition_time.clone())
+        .unwrap_or_else(|| Utc::now().to_rfc3339());
+
+    KupoPortCondition {
+        type_: API_KEYS_VALID_CONDITION.to_string(),
+        status: status.to_string(),
+        reason: reason.to_string(),
+        message: message.to_string(),
+        last_transition_time,
+    }
+}
+
+/// Key names label the proxy metrics and identify keys on revocation, so they must be unique,
+/// and `default` is taken by the port main key.
+fn validate_api_key_names(crd: &KupoPort) -> Result<()> {
+    let mut names = BTreeSet::from([DEFAULT_API_KEY_NAME]);
+    for api_key in crd.spec.api_keys.iter() {
+        if !names.insert(api_key.name.as_str()) {
+            return Err(Error::InvalidSpec(format!(
+                "api key name {} is used more than once",
+                api_key.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct KupoPortApiKeyStatus {
+    pub name: String,
+    pub fingerprint: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct KupoPortPreviousKey {
+    #[serde(default = "default_api_key_name")]
+    pub name: String,
+    #[serde(default)]
+    pub fingerprint: String,
+    /// RFC 3339 timestamp after which the key is no longer accepted.
+    pub expires_at: String,
+    /// Key published by versions that kept keys in the status, only read to move it to the
+    /// Secret and never written back.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
 }
 
 async fn reconcile(crd: Arc<KupoPort>, ctx: Arc<Context>) -> Result<Action> {
+    let namespace = crd.namespace().unwrap();
+
+    if let Err(err) = validate_api_key_names(&crd) {
+        let condition =
+            build_api_keys_condition(&crd, false, "DuplicateApiKeyName", &err.to_string());
+        let payload = serde_json::json!({ "conditions": [condition] });
+        patch_resource_status(
+            ctx.client.clone(),
+            &namespace,
+            KupoPort::api_resource(),
+            &crd.name_any(),
+            payload,
+        )
+        .await?;
+        return Err(err);
+    }
+
     let key = match &crd.spec.auth_token {
         Some(key) => key.clone(),
         None => build_api_key(&crd).await?,
     };
 
-    let (hostname, hostname_key) = build_hostname(&crd.spec.network, &key, &crd.spec.kupo_version);
+    let hostnames = build_hostnames(&crd.spec.network, &key, &crd.spec.kupo_version);
+    let (hostname, hostname_key) = hostnames[0].clone();
 
-    let status = KupoPortStatus {
-        endpoint_url: format!("https://{hostname}",),
+    let current_keys = get_auth_secret(ctx.client.clone(), &namespace, &crd.name_any())
+        .await?
+        .and_then(|secret| KupoPortKeys::from_secret(&secret))
+        .or_else(|| legacy_port_keys(&crd));
+
+    let mut api_keys = vec![];
+    for api_key in crd.spec.api_keys.iter() {
+        let auth_token = match &api_key.secret_ref {
+            Some(secret_ref) => {
+                let value = get_secret_value(
+                    ctx.client.clone(),
+                    &namespace,
+                    &secret_ref.name,
+                    &secret_ref.key,
+                )
+                .await?;
+                let Some(value) = value else {
+                    warn!(
+                        resource = crd.name_any(),
+                        key = api_key.name,
+                        secret = secret_ref.name,
+                        "api key secret not found, skipping it"
+                    );
+                    continue;
+                };
+                value
+            }
+            None => build_named_api_key(&crd, &api_key.name).await?,
+        };
+
+        api_keys.push(NamedApiKey {
+            name: api_key.name.clone(),
+            auth_token,
+        });
+    }
+
+    let mut keys = KupoPortKeys {
         authenticated_endpoint_url: format!("https://{hostname_key}").into(),
+        authenticated_endpoint_urls: hostnames
+            .iter()
+            .map(|(_, hostname_key)| format!("https://{hostname_key}"))
+            .collect(),
         auth_token: key,
+        api_keys,
+        previous_auth_tokens: vec![],
+    };
+    keys.previous_auth_tokens = build_previous_api_keys(current_keys.as_ref(), &keys);
+    let next_expiration = next_api_key_expiration(&keys.previous_auth_tokens);
+
+    let secret = keys.to_secret(&crd);
+    apply_auth_secret(ctx.client.clone(), &secret).await?;
+
+    let status = KupoPortStatus {
+        endpoint_url: format!("https://{hostname}",),
+        endpoint_urls: hostnames
+            .iter()
+            .map(|(hostname, _)| format!("https://{hostname}"))
+            .collect(),
+        auth_token_secret: Some(secret.name_any()),
+        auth_token_fingerprint: Some(build_api_key_fingerprint(&keys.auth_token)),
+        auth_token: Some(keys.auth_token.clone()),
+        auth_token_hash: Some(build_api_key_hash(&keys.auth_token)),
+        authenticated_endpoint_url: keys.authenticated_endpoint_url.clone(),
+        api_keys: keys.api_key_fingerprints(),
+        previous_auth_tokens: keys.previous_fingerprints(),
+        conditions: vec![build_api_keys_condition(&crd, true, "Reconciled", "")],
     };
 
-    let namespace = crd.namespace().unwrap();
     let kupo_port = KupoPort::api_resource();
 
     patch_resource_status(
@@ -86,12 +323,23 @@ async fn reconcile(crd: Arc<KupoPort>, ctx: Arc<Context>) -> Result<Action> {
 
     info!(resource = crd.name_any(), "Reconcile completed");
 
-    Ok(Action::await_change())
+    // Come back when a rotated key expires so it is dropped from the Secret, and regularly
+    // when keys are referenced from Secrets the operator doesn't own, to pick up their changes.
+    let has_secret_refs = crd.spec.api_keys.iter().any(|key| key.secret_ref.is_some());
+    let next_resync = has_secret_refs.then_some(SECRET_REF_RESYNC_INTERVAL);
+    match next_expiration.into_iter().chain(next_resync).min() {
+        Some(requeue) => Ok(Action::requeue(requeue)),
+        None => Ok(Action::await_change()),
+    }
 }
 
 fn error_policy(crd: Arc<KupoPort>, err: &Error, ctx: Arc<Context>) -> Action {
     error!(error = err.to_string(), "reconcile failed");
     ctx.metrics.reconcile_failure(&crd, err);
+    // An invalid spec only gets reconciled again once the port is edited.
+    if let Error::InvalidSpec(_) = err {
+        return Action::await_change();
+    }
     Action::requeue(Duration::from_secs(5))
 }
 
@@ -104,10 +352,13 @@ pub async fn run(state: Arc<State>) {
         .expect("failed to create kube client");
 
     let crds = Api::<KupoPort>::all(client.clone());
+    // Reconcile the port again when its key Secret is edited or removed.
+    let secrets = Api::<Secret>::all(client.clone());
 
     let ctx = Context::new(client, state.metrics.clone());
 
     Controller::new(crds, WatcherConfig::default().any_semantic())
+        .owns(secrets, WatcherConfig::default().labels(AUTH_SECRET_LABEL))
         .shutdown_on_signal()
         .run(reconcile, error_policy, Arc::new(ctx))
         .filter_map(|x| async move { std::result::Result::ok(x) })
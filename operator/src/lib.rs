@@ -14,6 +14,9 @@ pub enum Error {
 
     #[error("Config Error: {0}")]
     ConfigError(String),
+
+    #[error("Invalid Spec: {0}")]
+    InvalidSpec(String),
 }
 
 impl Error {
@@ -78,3 +81,6 @@ pub use config::*;
 
 mod utils;
 pub use utils::*;
+
+mod auth;
+pub use auth::*;
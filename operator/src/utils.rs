@@ -1,6 +1,7 @@
 use argon2::Argon2;
 use base64::{engine::general_purpose, Engine};
 use bech32::ToBase32;
+use chrono::{DateTime, Utc};
 use kube::{
     api::{Patch, PatchParams},
     core::DynamicObject,
@@ -8,8 +9,11 @@ use kube::{
     Api, Client, ResourceExt,
 };
 use serde_json::json;
+use sha2::{Digest, Sha256};
 
-use crate::{get_config, Error, KupoPort};
+use std::time::Duration;
+
+use crate::{get_config, Error, KupoPort, PreviousApiKey, DEFAULT_API_KEY_NAME};
 
 pub async fn patch_resource_status(
     client: Client,
@@ -29,24 +33,55 @@ pub async fn patch_resource_status(
 
 pub fn build_hostname(network: &str, key: &str, kupo_version: &Option<String>) -> (String, String) {
     let config = get_config();
-    let extension_subdomain = &config.extension_subdomain;
-    let dns_zone = &config.dns_zone;
+    let zone = format!("{}.{}", config.extension_subdomain, config.dns_zone);
+    build_zone_hostname(&zone, network, key, kupo_version)
+}
+
+/// Hostnames of the port in the main zone, followed by the ones in `EXTRA_ZONES`.
+pub fn build_hostnames(
+    network: &str,
+    key: &str,
+    kupo_version: &Option<String>,
+) -> Vec<(String, String)> {
+    let mut hostnames = vec![build_hostname(network, key, kupo_version)];
+    hostnames.extend(
+        get_config()
+            .extra_zones
+            .iter()
+            .map(|zone| build_zone_hostname(zone, network, key, kupo_version)),
+    );
+    hostnames
+}
+
+fn build_zone_hostname(
+    zone: &str,
+    network: &str,
+    key: &str,
+    kupo_version: &Option<String>,
+) -> (String, String) {
     let version = kupo_version
         .clone()
-        .unwrap_or(config.default_kupo_version.to_string());
+        .unwrap_or(get_config().default_kupo_version.to_string());
 
-    let hostname = format!("{network}-{version}.{extension_subdomain}.{dns_zone}");
-    let hostname_key = format!("{key}.{network}-{version}.{extension_subdomain}.{dns_zone}");
+    let hostname = format!("{network}-{version}.{zone}");
+    let hostname_key = format!("{key}.{network}-{version}.{zone}");
 
     (hostname, hostname_key)
 }
 
 pub async fn build_api_key(crd: &KupoPort) -> Result<String, Error> {
-    let namespace = crd.namespace().unwrap();
-
-    let name = format!("kupo-auth-{}", &crd.name_any());
+    build_named_api_key(crd, DEFAULT_API_KEY_NAME).await
+}
 
-    let password = format!("{}{}", name, namespace).as_bytes().to_vec();
+pub async fn build_named_api_key(crd: &KupoPort, key_name: &str) -> Result<String, Error> {
+    let namespace = crd.namespace().unwrap();
+    let password = build_api_key_password(
+        &crd.name_any(),
+        &namespace,
+        key_name,
+        crd.spec.key_generation.unwrap_or_default(),
+    );
+    let password = password.as_bytes().to_vec();
 
     let config = get_config();
     let salt = config.api_key_salt.as_bytes();
@@ -62,3 +97,83 @@ pub async fn build_api_key(crd: &KupoPort) -> Result<String, Error> {
 
     Ok(with_bech)
 }
+
+/// Input the key is derived from. The default key at generation 0 keeps the key ports had before
+/// named keys and rotation were supported. The key name and generation are delimited, so they
+/// can't be confused with a longer namespace or key name.
+fn build_api_key_password(
+    port_name: &str,
+    namespace: &str,
+    key_name: &str,
+    generation: u32,
+) -> String {
+    let mut password = format!("kupo-auth-{port_name}{namespace}");
+    if key_name != DEFAULT_API_KEY_NAME {
+        password.push_str(&format!(":{key_name}"));
+    }
+    if generation > 0 {
+        password.push_str(&format!("#gen{generation}"));
+    }
+    password
+}
+
+/// Hex encoded SHA-256 of an API key. The proxy only keeps this digest in memory, so the raw
+/// key never ends up in its consumer table, limiter state or Redis.
+pub fn build_api_key_hash(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// Short prefix of the key hash, enough to tell keys apart in the port status.
+pub fn build_api_key_fingerprint(key: &str) -> String {
+    build_api_key_hash(key)[..16].to_string()
+}
+
+/// Time left until the first of the previous keys expires.
+pub fn next_api_key_expiration(previous_keys: &[PreviousApiKey]) -> Option<Duration> {
+    let now = Utc::now();
+    previous_keys
+        .iter()
+        .filter_map(|previous| parse_expiration(&previous.expires_at))
+        .min()
+        .map(|expiration| (expiration - now).to_std().unwrap_or_default())
+        .map(|remaining| remaining.max(Duration::from_secs(1)))
+}
+
+pub fn parse_expiration(expires_at: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(expires_at)
+        .ok()
+        .map(|expiration| expiration.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_key_at_generation_zero_is_unchanged() {
+        assert_eq!(
+            build_api_key_password("foo", "prj-a", DEFAULT_API_KEY_NAME, 0),
+            "kupo-auth-fooprj-a"
+        );
+    }
+
+    #[test]
+    fn generation_does_not_collide_with_namespace() {
+        assert_ne!(
+            build_api_key_password("foo", "prj-a", DEFAULT_API_KEY_NAME, 1),
+            build_api_key_password("foo", "prj-a1", DEFAULT_API_KEY_NAME, 0)
+        );
+    }
+
+    #[test]
+    fn generation_does_not_collide_with_key_name() {
+        assert_ne!(
+            build_api_key_password("foo", "prj-a", "ci", 1),
+            build_api_key_password("foo", "prj-a", "ci1", 0)
+        );
+        assert_ne!(
+            build_api_key_password("foo", "prj-a", "ci", 11),
+            build_api_key_password("foo", "prj-a", "ci1", 1)
+        );
+    }
+}
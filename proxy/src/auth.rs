@@ -1,16 +1,46 @@
-use crate::{Consumer, State};
+use crate::{port_limiter_key, Consumer, HashedKey, State};
 use async_trait::async_trait;
-use futures_util::TryStreamExt;
+use futures_util::{stream, StreamExt};
 use kube::{
     runtime::watcher::{self, Config as ConfigWatcher, Event},
     Api, Client, ResourceExt,
 };
-use operator::KupoPort;
+use operator::{k8s_openapi::api::core::v1::Secret, KupoPort, KupoPortKeys, AUTH_SECRET_LABEL};
 use pingora::{server::ShutdownWatch, services::background::BackgroundService};
 use std::{collections::HashMap, sync::Arc};
 use tokio::pin;
 use tracing::{error, info};
 
+/// Namespace and name of a port.
+type PortId = (String, String);
+
+enum AuthEvent {
+    Port(Result<Event<KupoPort>, watcher::Error>),
+    Secret(Result<Event<Secret>, watcher::Error>),
+}
+
+fn port_id(port: &KupoPort) -> PortId {
+    (port.namespace().unwrap_or_default(), port.name_any())
+}
+
+/// Hashes the keys the port status may still hold, and drops the raw ones from the port.
+fn take_status_keys(port: &mut KupoPort) -> Vec<HashedKey> {
+    let keys = HashedKey::from_port_status(port);
+    if let Some(status) = port.status.as_mut() {
+        status.auth_token = None;
+        for previous in status.previous_auth_tokens.iter_mut() {
+            previous.auth_token = None;
+        }
+    }
+    keys
+}
+
+/// Secrets are matched to their port by the label the operator sets on them.
+fn secret_port_id(secret: &Secret) -> Option<PortId> {
+    let port_name = secret.labels().get(AUTH_SECRET_LABEL)?.clone();
+    Some((secret.namespace().unwrap_or_default(), port_name))
+}
+
 pub struct AuthBackgroundService {
     state: Arc<State>,
 }
@@ -18,6 +48,48 @@ impl AuthBackgroundService {
     pub fn new(state: Arc<State>) -> Self {
         Self { state }
     }
+
+    /// Consumers are created once the port is known, with the keys of its Secret or, until the
+    /// Secret is received, the ones of its status.
+    async fn update_port(
+        &self,
+        id: &PortId,
+        port: Option<&(KupoPort, Vec<HashedKey>)>,
+        keys: Option<&Vec<HashedKey>>,
+    ) {
+        let consumers = match port {
+            Some((port, status_keys)) => Consumer::keys(port, keys.unwrap_or(status_keys)),
+            None => vec![],
+        };
+        self.state.set_port_consumers(&id.0, &id.1, consumers).await;
+    }
+
+    /// Concurrency slots are kept, they follow the tier limit of the next requests.
+    async fn reset_limits(&self, id: &PortId) {
+        let limiter_key = port_limiter_key(&id.0, &id.1);
+        self.state.limiter.remove(&limiter_key).await;
+    }
+
+    /// Shared counters are only deleted with the port, updates reach every replica.
+    async fn delete_limits(&self, id: &PortId) {
+        let limiter_key = port_limiter_key(&id.0, &id.1);
+        self.state.limiter.delete(&limiter_key).await;
+        self.state.concurrency.remove(&limiter_key).await;
+    }
+
+    async fn reset_consumers(
+        &self,
+        ports: &HashMap<PortId, (KupoPort, Vec<HashedKey>)>,
+        keys: &HashMap<PortId, Vec<HashedKey>>,
+    ) {
+        let consumers: HashMap<String, Consumer> = ports
+            .iter()
+            .flat_map(|(id, (port, status_keys))| {
+                Consumer::keys(port, keys.get(id).unwrap_or(status_keys))
+            })
+            .collect();
+        *self.state.consumers.write().await = consumers;
+    }
 }
 
 #[async_trait]
@@ -27,61 +99,108 @@ impl BackgroundService for AuthBackgroundService {
             .await
             .expect("failed to create kube client");
 
-        let api = Api::<KupoPort>::all(client.clone());
-        let stream = watcher::watcher(api.clone(), ConfigWatcher::default());
+        let port_api = Api::<KupoPort>::all(client.clone());
+        let port_stream = watcher::watcher(port_api, ConfigWatcher::default()).map(AuthEvent::Port);
+
+        let secret_api = Api::<Secret>::all(client.clone());
+        let secret_stream = watcher::watcher(
+            secret_api,
+            ConfigWatcher::default().labels(AUTH_SECRET_LABEL),
+        )
+        .map(AuthEvent::Secret);
+
+        let stream = stream::select(port_stream, secret_stream);
         pin!(stream);
 
+        // Ports with the hashed keys of their status, kept while their Secret is missing.
+        let mut ports: HashMap<PortId, (KupoPort, Vec<HashedKey>)> = HashMap::new();
+        // Only hashes are kept, the raw keys of a Secret are dropped once it's processed.
+        let mut keys: HashMap<PortId, Vec<HashedKey>> = HashMap::new();
+
         loop {
-            let result = stream.try_next().await;
+            let result = stream.next().await;
             match result {
                 // Stream restart, also run on startup.
-                Ok(Some(Event::Restarted(crds))) => {
+                Some(AuthEvent::Port(Ok(Event::Restarted(crds)))) => {
                     info!("auth: Watcher restarted, reseting consumers");
-                    let consumers: HashMap<String, Consumer> = crds
+                    ports = crds
+                        .into_iter()
+                        .map(|mut crd| {
+                            let status_keys = take_status_keys(&mut crd);
+                            (port_id(&crd), (crd, status_keys))
+                        })
+                        .collect();
+                    self.reset_consumers(&ports, &keys).await;
+                    self.state.limiter.clear().await;
+                }
+                Some(AuthEvent::Secret(Ok(Event::Restarted(secrets)))) => {
+                    info!("auth: Secret watcher restarted, reseting consumers");
+                    keys = secrets
                         .iter()
-                        .map(|crd| {
-                            let consumer = Consumer::from(crd);
-                            (consumer.key.clone(), consumer)
+                        .filter_map(|secret| {
+                            let port_keys = KupoPortKeys::from_secret(secret)?;
+                            Some((
+                                secret_port_id(secret)?,
+                                HashedKey::from_port_keys(&port_keys),
+                            ))
                         })
                         .collect();
-                    *self.state.consumers.write().await = consumers;
-                    self.state.limiter.write().await.clear();
+                    self.reset_consumers(&ports, &keys).await;
                 }
                 // New port created or updated.
-                Ok(Some(Event::Applied(crd))) => match crd.status {
-                    Some(_) => {
-                        info!("auth: Updating consumer: {}", crd.name_any());
-                        let consumer = Consumer::from(&crd);
-                        self.state.limiter.write().await.remove(&consumer.key);
-                        self.state
-                            .consumers
-                            .write()
-                            .await
-                            .insert(consumer.key.clone(), consumer);
-                    }
-                    None => {
-                        // New ports are created without status. When the status is added, a new
-                        // Applied event is triggered.
-                        info!("auth: New port created: {}", crd.name_any());
+                Some(AuthEvent::Port(Ok(Event::Applied(mut crd)))) => {
+                    info!("auth: Updating consumer: {}", crd.name_any());
+                    let id = port_id(&crd);
+                    self.reset_limits(&id).await;
+                    let status_keys = take_status_keys(&mut crd);
+                    ports.insert(id.clone(), (crd, status_keys));
+                    self.update_port(&id, ports.get(&id), keys.get(&id)).await;
+                }
+                // Port keys created or rotated. New ports get their Secret after being
+                // reconciled by the operator.
+                Some(AuthEvent::Secret(Ok(Event::Applied(secret)))) => {
+                    let Some(id) = secret_port_id(&secret) else {
+                        continue;
+                    };
+                    info!("auth: Updating keys: {}", id.1);
+                    match KupoPortKeys::from_secret(&secret) {
+                        Some(port_keys) => {
+                            keys.insert(id.clone(), HashedKey::from_port_keys(&port_keys));
+                        }
+                        None => {
+                            keys.remove(&id);
+                        }
                     }
-                },
+                    self.update_port(&id, ports.get(&id), keys.get(&id)).await;
+                }
                 // Port deleted.
-                Ok(Some(Event::Deleted(crd))) => {
+                Some(AuthEvent::Port(Ok(Event::Deleted(crd)))) => {
                     info!(
                         "auth: Port deleted, removing from state: {}",
                         crd.name_any()
                     );
-                    let consumer = Consumer::from(&crd);
-                    self.state.consumers.write().await.remove(&consumer.key);
-                    self.state.limiter.write().await.remove(&consumer.key);
+                    let id = port_id(&crd);
+                    ports.remove(&id);
+                    self.update_port(&id, None, None).await;
+                    self.delete_limits(&id).await;
+                }
+                // Port keys deleted, the operator recreates them on its next reconcile. The keys of
+                // the port status are used meanwhile.
+                Some(AuthEvent::Secret(Ok(Event::Deleted(secret)))) => {
+                    let Some(id) = secret_port_id(&secret) else {
+                        continue;
+                    };
+                    info!("auth: Keys deleted, removing from state: {}", id.1);
+                    keys.remove(&id);
+                    self.update_port(&id, ports.get(&id), None).await;
                 }
                 // Empty response from stream. Should never happen.
-                Ok(None) => {
+                None => {
                     error!("auth: Empty response from watcher.");
                     continue;
                 }
-                // Unexpected error when streaming CRDs.
-                Err(err) => match err {
+                // Unexpected error when streaming CRDs or Secrets.
+                Some(AuthEvent::Port(Err(err)) | AuthEvent::Secret(Err(err))) => match err {
                     watcher::Error::WatchError(status) => {
                         error!(
                             code = status.code,
@@ -0,0 +1,57 @@
+use pingora::cache::{eviction::simple_lru, lock::CacheLock, MemCache};
+use regex::Regex;
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::deserialize_regex;
+
+/// Route whose `GET` responses can be cached, matched against the request path.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheRule {
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub path: Regex,
+    /// Seconds a cached response stays fresh.
+    pub ttl: u64,
+}
+
+/// Upper bound of how long a coalesced response stays in the storage. It is only served to
+/// requests that were already waiting for it, later ones fetch from the upstream again.
+pub const COALESCE_FRESHNESS: Duration = Duration::from_secs(1);
+
+/// In-memory cache of upstream responses, bounded by an LRU memory budget. With coalescing, the
+/// cache lock makes identical requests wait for the one already fetching from the upstream and
+/// share its response.
+pub struct ResponseCache {
+    pub storage: &'static MemCache,
+    pub eviction: &'static simple_lru::Manager,
+    pub lock: Option<&'static CacheLock>,
+    rules: Vec<CacheRule>,
+}
+impl ResponseCache {
+    pub fn new(max_size: usize, rules: Vec<CacheRule>, coalesce_timeout: Option<Duration>) -> Self {
+        // The cache hooks of pingora require storages that live as long as the server.
+        let storage = Box::leak(Box::new(MemCache::new()));
+        let eviction = Box::leak(Box::new(simple_lru::Manager::new(max_size)));
+        let lock = coalesce_timeout.map(|timeout| &*Box::leak(Box::new(CacheLock::new(timeout))));
+
+        Self {
+            storage,
+            eviction,
+            lock,
+            rules,
+        }
+    }
+
+    /// TTL of the first rule matching the path, `None` when the route is not cached.
+    pub fn ttl(&self, path: &str) -> Option<Duration> {
+        self.rules
+            .iter()
+            .find(|rule| rule.path.is_match(path))
+            .map(|rule| Duration::from_secs(rule.ttl))
+    }
+
+    /// Routes without a rule are still coalesced when the cache lock is enabled.
+    pub fn coalesces(&self) -> bool {
+        self.lock.is_some()
+    }
+}
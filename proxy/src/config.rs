@@ -1,22 +1,51 @@
-use std::{collections::HashMap, env, path::PathBuf, time::Duration};
+use ipnet::IpNet;
+use regex::Regex;
+use serde::Deserialize;
+use std::{collections::HashMap, env, net::IpAddr, path::PathBuf, time::Duration};
+
+use crate::cache::CacheRule;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub proxy_addr: String,
+    pub proxy_plain_addr: Option<String>,
+    pub proxy_h2: bool,
     pub proxy_namespace: String,
     pub proxy_tiers_path: PathBuf,
     pub proxy_tiers_poll_interval: Duration,
+    pub proxy_quota_state_path: Option<PathBuf>,
+    pub proxy_quota_persist_interval: Duration,
     pub prometheus_addr: String,
+    pub limiter_redis_url: Option<String>,
+    pub limiter_redis_prefix: String,
+    pub limiter_redis_timeout: Duration,
     pub ssl_crt_path: String,
     pub ssl_key_path: String,
-    pub kupo_instances: HashMap<String, String>,
+    pub ssl_poll_interval: Duration,
+    pub ssl_certificates: Vec<TlsCertificatePaths>,
+    pub kupo_instances: Vec<KupoInstance>,
+    pub default_kupo_version: String,
+    pub upstream_discovery_interval: Duration,
+    pub upstream_health_check_interval: Duration,
+    pub upstream_h2c: bool,
 
     // Health endpoint
     pub health_endpoint: String,
     pub health_network: String,
     pub health_poll_interval: std::time::Duration,
+    pub health_min_sync_ratio: Option<f64>,
+    pub health_max_checkpoint_gap: Option<u64>,
+    pub health_max_block_age: Option<i32>,
     pub private_endpoint: String,
 
+    // Client address
+    pub trusted_proxies: Vec<IpNet>,
+
+    // Response cache
+    pub cache_max_size: Option<usize>,
+    pub cache_rules: Vec<CacheRule>,
+    pub coalesce_timeout: Option<Duration>,
+
     // CORS configuration
     pub cors_allow_origin: String,
     pub cors_allow_methods: String,
@@ -26,14 +55,36 @@ pub struct Config {
 impl Config {
     pub fn new() -> Self {
         let kupo_instances = env::var("KUPO_INSTANCES").expect("KUPO_INSTANCES must be set");
-        let kupo_instances = serde_json::from_str::<HashMap<String, String>>(&kupo_instances)
-            .expect("KUPO_INSTANCES must be valid JSON map of network to host:port");
+        let kupo_instances: Vec<KupoInstance> = serde_json::from_str::<KupoInstances>(
+            &kupo_instances,
+        )
+        .expect(
+            "KUPO_INSTANCES must be a valid JSON list of instances or map of network to host:port",
+        )
+        .into();
+        validate_kupo_instances(&kupo_instances);
+
+        let cache_tip_ttl = env::var("CACHE_TIP_TTL")
+            .map(|v| {
+                v.parse::<u64>()
+                    .expect("CACHE_TIP_TTL must be a number in seconds. eg: 30")
+            })
+            .unwrap_or(30);
+        let cache_rules = match env::var("CACHE_RULES") {
+            Ok(rules) => serde_json::from_str::<Vec<CacheRule>>(&rules)
+                .expect("CACHE_RULES must be a valid JSON list of path regex and ttl"),
+            Err(_) => default_cache_rules(cache_tip_ttl),
+        };
 
         let private_endpoint = env::var("KUPO_PRIVATE_ENDPOINT_REGEX")
             .unwrap_or(r"^PUT/patterns(?:/.*)?$".to_string());
 
         Self {
             proxy_addr: env::var("PROXY_ADDR").expect("PROXY_ADDR must be set"),
+            proxy_plain_addr: env::var("PROXY_PLAIN_ADDR").ok(),
+            proxy_h2: env::var("PROXY_H2")
+                .map(|v| v.parse::<bool>().expect("PROXY_H2 must be true or false"))
+                .unwrap_or(false),
             proxy_namespace: env::var("PROXY_NAMESPACE").expect("PROXY_NAMESPACE must be set"),
             proxy_tiers_path: env::var("PROXY_TIERS_PATH")
                 .map(|v| v.into())
@@ -46,10 +97,64 @@ impl Config {
                     )
                 })
                 .unwrap_or(Duration::from_secs(2)),
+            proxy_quota_state_path: env::var("PROXY_QUOTA_STATE_PATH").ok().map(|v| v.into()),
+            proxy_quota_persist_interval: env::var("PROXY_QUOTA_PERSIST_INTERVAL")
+                .map(|v| {
+                    Duration::from_secs(
+                        v.parse::<u64>().expect(
+                            "PROXY_QUOTA_PERSIST_INTERVAL must be a number in seconds. eg: 30",
+                        ),
+                    )
+                })
+                .unwrap_or(Duration::from_secs(30)),
             prometheus_addr: env::var("PROMETHEUS_ADDR").expect("PROMETHEUS_ADDR must be set"),
+            limiter_redis_url: env::var("LIMITER_REDIS_URL").ok(),
+            limiter_redis_prefix: env::var("LIMITER_REDIS_PREFIX")
+                .unwrap_or("kupo-proxy:limiter".to_string()),
+            limiter_redis_timeout: env::var("LIMITER_REDIS_TIMEOUT")
+                .map(|v| {
+                    Duration::from_millis(v.parse::<u64>().expect(
+                        "LIMITER_REDIS_TIMEOUT must be a number in milliseconds. eg: 200",
+                    ))
+                })
+                .unwrap_or(Duration::from_millis(200)),
             ssl_crt_path: env::var("SSL_CRT_PATH").expect("SSL_CRT_PATH must be set"),
             ssl_key_path: env::var("SSL_KEY_PATH").expect("SSL_KEY_PATH must be set"),
+            ssl_poll_interval: env::var("SSL_POLL_INTERVAL")
+                .map(|v| {
+                    Duration::from_secs(
+                        v.parse::<u64>()
+                            .expect("SSL_POLL_INTERVAL must be a number in seconds. eg: 30"),
+                    )
+                })
+                .unwrap_or(Duration::from_secs(30)),
+            ssl_certificates: env::var("SSL_CERTIFICATES")
+                .map(|v| {
+                    serde_json::from_str(&v)
+                        .expect("SSL_CERTIFICATES must be a valid JSON list of crt_path and key_path")
+                })
+                .unwrap_or_default(),
             kupo_instances,
+            default_kupo_version: env::var("DEFAULT_KUPO_VERSION").unwrap_or("v2".to_string()),
+            upstream_discovery_interval: env::var("UPSTREAM_DISCOVERY_INTERVAL")
+                .map(|v| {
+                    Duration::from_secs(
+                        v.parse::<u64>().expect(
+                            "UPSTREAM_DISCOVERY_INTERVAL must be a number in seconds. eg: 30",
+                        ),
+                    )
+                })
+                .unwrap_or(Duration::from_secs(30)),
+            upstream_health_check_interval: env::var("UPSTREAM_HEALTH_CHECK_INTERVAL")
+                .map(|v| {
+                    Duration::from_secs(v.parse::<u64>().expect(
+                        "UPSTREAM_HEALTH_CHECK_INTERVAL must be a number in seconds. eg: 5",
+                    ))
+                })
+                .unwrap_or(Duration::from_secs(5)),
+            upstream_h2c: env::var("UPSTREAM_H2C")
+                .map(|v| v.parse::<bool>().expect("UPSTREAM_H2C must be true or false"))
+                .unwrap_or(false),
             health_endpoint: "/dmtr_health".to_string(),
             health_network: env::var("HEALTH_NETWORK").unwrap_or("cardano-mainnet".to_string()),
             health_poll_interval: env::var("HEALTH_POLL_INTERVAL")
@@ -60,8 +165,48 @@ impl Config {
                     )
                 })
                 .unwrap_or(Duration::from_secs(10)),
+            health_min_sync_ratio: env::var("HEALTH_MIN_SYNC_RATIO").ok().map(|v| {
+                v.parse::<f64>()
+                    .expect("HEALTH_MIN_SYNC_RATIO must be a ratio between 0 and 1. eg: 0.999")
+            }),
+            health_max_checkpoint_gap: env::var("HEALTH_MAX_CHECKPOINT_GAP").ok().map(|v| {
+                v.parse::<u64>()
+                    .expect("HEALTH_MAX_CHECKPOINT_GAP must be a number of slots. eg: 600")
+            }),
+            health_max_block_age: env::var("HEALTH_MAX_BLOCK_AGE").ok().map(|v| {
+                v.parse::<i32>()
+                    .expect("HEALTH_MAX_BLOCK_AGE must be a number in seconds. eg: 300")
+            }),
             private_endpoint,
 
+            // Client address
+            trusted_proxies: env::var("TRUSTED_PROXIES")
+                .map(|v| {
+                    v.split(',')
+                        .map(|cidr| cidr.trim())
+                        .filter(|cidr| !cidr.is_empty())
+                        .map(|cidr| {
+                            parse_ip_net(cidr).expect(
+                                "TRUSTED_PROXIES must be a comma separated list of CIDRs. eg: 10.0.0.0/8",
+                            )
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+
+            // Response cache
+            cache_max_size: env::var("CACHE_MAX_SIZE").ok().map(|v| {
+                v.parse::<usize>()
+                    .expect("CACHE_MAX_SIZE must be a number in bytes. eg: 67108864")
+            }),
+            cache_rules,
+            coalesce_timeout: env::var("COALESCE_TIMEOUT").ok().map(|v| {
+                Duration::from_secs(
+                    v.parse::<u64>()
+                        .expect("COALESCE_TIMEOUT must be a number in seconds. eg: 10"),
+                )
+            }),
+
             // CORS configuration
             cors_allow_origin: env::var("CORS_ALLOW_ORIGIN").unwrap_or("*".to_string()),
             cors_allow_methods: env::var("CORS_ALLOW_METHODS")
@@ -71,6 +216,36 @@ impl Config {
             cors_max_age: env::var("CORS_MAX_AGE").unwrap_or("86400".to_string()),
         }
     }
+
+    /// Every certificate served, starting with the default one from `SSL_CRT_PATH`.
+    pub fn tls_certificate_paths(&self) -> Vec<TlsCertificatePaths> {
+        let default = TlsCertificatePaths {
+            crt_path: self.ssl_crt_path.clone(),
+            key_path: self.ssl_key_path.clone(),
+        };
+        std::iter::once(default)
+            .chain(self.ssl_certificates.iter().cloned())
+            .collect()
+    }
+
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Picks the Kupo instance that should serve a consumer.
+    ///
+    /// Entries that leave `pruned` or `version` unset match any value. When more than one entry
+    /// matches, the most specific one wins. A pruned consumer falls back to an unpruned instance
+    /// of the same network and version, but an unpruned consumer is never routed to a pruned
+    /// instance since it would miss spent outputs.
+    pub fn kupo_instance(
+        &self,
+        network: &str,
+        pruned: bool,
+        version: &str,
+    ) -> Option<&KupoInstance> {
+        route_kupo_instance(&self.kupo_instances, network, pruned, version)
+    }
 }
 
 impl Default for Config {
@@ -78,3 +253,218 @@ impl Default for Config {
         Self::new()
     }
 }
+
+fn route_kupo_instance<'a>(
+    instances: &'a [KupoInstance],
+    network: &str,
+    pruned: bool,
+    version: &str,
+) -> Option<&'a KupoInstance> {
+    let candidates = instances
+        .iter()
+        .filter(|i| i.network == network)
+        .filter(|i| i.version.as_deref().is_none_or(|v| v == version));
+
+    candidates
+        .clone()
+        .filter(|i| i.pruned.is_none_or(|p| p == pruned))
+        .max_by_key(|i| i.specificity())
+        .or_else(|| {
+            if !pruned {
+                return None;
+            }
+            candidates
+                .filter(|i| i.pruned == Some(false))
+                .max_by_key(|i| i.specificity())
+        })
+}
+
+/// Scripts and datums are content addressed and never change. Metadata of a slot may still be
+/// rolled back near the tip, so it is only kept for `tip_ttl` seconds.
+fn default_cache_rules(tip_ttl: u64) -> Vec<CacheRule> {
+    vec![
+        CacheRule {
+            path: Regex::new(r"^/scripts/[0-9a-fA-F]{56}$").unwrap(),
+            ttl: 86400,
+        },
+        CacheRule {
+            path: Regex::new(r"^/datums/[0-9a-fA-F]{64}$").unwrap(),
+            ttl: 86400,
+        },
+        CacheRule {
+            path: Regex::new(r"^/metadata/\d+$").unwrap(),
+            ttl: tip_ttl,
+        },
+    ]
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsCertificatePaths {
+    pub crt_path: String,
+    pub key_path: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KupoInstance {
+    pub network: String,
+    pub pruned: Option<bool>,
+    pub version: Option<String>,
+    pub address: Option<String>,
+    #[serde(default)]
+    pub upstreams: Vec<KupoUpstream>,
+}
+impl KupoInstance {
+    /// Identifies the routing entry, entries with the same network, prune flag and version are
+    /// expected to be declared once.
+    pub fn id(&self) -> String {
+        let pruned = self.pruned.map_or("*".to_string(), |p| p.to_string());
+        let version = self.version.as_deref().unwrap_or("*");
+        format!("{}.{pruned}.{version}", self.network)
+    }
+
+    /// All upstreams of the entry, `address` is shorthand for a single upstream of weight 1.
+    pub fn upstreams(&self) -> Vec<KupoUpstream> {
+        self.address
+            .iter()
+            .map(|address| KupoUpstream {
+                address: address.clone(),
+                weight: 1,
+            })
+            .chain(self.upstreams.iter().cloned())
+            .collect()
+    }
+
+    fn specificity(&self) -> u8 {
+        self.pruned.is_some() as u8 + self.version.is_some() as u8
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KupoUpstream {
+    pub address: String,
+    #[serde(default = "default_upstream_weight")]
+    pub weight: usize,
+}
+fn default_upstream_weight() -> usize {
+    1
+}
+
+/// Instances without upstreams would only fail at request time, so they are rejected on startup.
+fn validate_kupo_instances(instances: &[KupoInstance]) {
+    for instance in instances {
+        if instance.upstreams().is_empty() {
+            panic!(
+                "KUPO_INSTANCES entry {} must have an address or a non-empty list of upstreams",
+                instance.id()
+            );
+        }
+    }
+}
+
+/// `KUPO_INSTANCES` accepts the routing table as a list of instances, or the legacy map of
+/// network to `host:port`, whose entries match any prune flag and version.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KupoInstances {
+    Table(Vec<KupoInstance>),
+    Legacy(HashMap<String, String>),
+}
+impl From<KupoInstances> for Vec<KupoInstance> {
+    fn from(value: KupoInstances) -> Self {
+        match value {
+            KupoInstances::Table(instances) => instances,
+            KupoInstances::Legacy(map) => map
+                .into_iter()
+                .map(|(network, address)| KupoInstance {
+                    network,
+                    pruned: None,
+                    version: None,
+                    address: Some(address),
+                    upstreams: Vec::new(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Parses a CIDR, a single address is taken as a network of its own.
+pub fn parse_ip_net(value: &str) -> Option<IpNet> {
+    value
+        .parse::<IpNet>()
+        .ok()
+        .or_else(|| value.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instances() -> Vec<KupoInstance> {
+        serde_json::from_str::<KupoInstances>(
+            r#"[
+                { "network": "mainnet", "address": "mainnet-any:1442" },
+                { "network": "mainnet", "pruned": true, "address": "mainnet-pruned:1442" },
+                { "network": "mainnet", "pruned": false, "version": "v2", "address": "mainnet-v2:1442" },
+                { "network": "preprod", "pruned": false, "address": "preprod-full:1442" },
+                { "network": "preview", "pruned": true, "address": "preview-pruned:1442" }
+            ]"#,
+        )
+        .unwrap()
+        .into()
+    }
+
+    fn route(network: &str, pruned: bool, version: &str) -> Option<String> {
+        route_kupo_instance(&instances(), network, pruned, version)
+            .and_then(|instance| instance.address.clone())
+    }
+
+    #[test]
+    fn most_specific_instance_wins() {
+        assert_eq!(
+            route("mainnet", true, "v1").as_deref(),
+            Some("mainnet-pruned:1442")
+        );
+        assert_eq!(
+            route("mainnet", false, "v2").as_deref(),
+            Some("mainnet-v2:1442")
+        );
+        assert_eq!(
+            route("mainnet", false, "v1").as_deref(),
+            Some("mainnet-any:1442")
+        );
+    }
+
+    #[test]
+    fn pruned_consumer_falls_back_to_unpruned_instance() {
+        assert_eq!(
+            route("preprod", true, "v1").as_deref(),
+            Some("preprod-full:1442")
+        );
+    }
+
+    #[test]
+    fn unpruned_consumer_is_never_routed_to_pruned_instance() {
+        assert_eq!(route("preview", false, "v1"), None);
+        assert_eq!(
+            route("preview", true, "v1").as_deref(),
+            Some("preview-pruned:1442")
+        );
+    }
+
+    #[test]
+    fn unknown_network_has_no_instance() {
+        assert_eq!(route("sanchonet", false, "v1"), None);
+    }
+
+    #[test]
+    fn legacy_map_matches_any_prune_flag_and_version() {
+        let instances: Vec<KupoInstance> =
+            serde_json::from_str::<KupoInstances>(r#"{ "mainnet": "mainnet:1442" }"#)
+                .unwrap()
+                .into();
+        for pruned in [true, false] {
+            let instance = route_kupo_instance(&instances, "mainnet", pruned, "v1").unwrap();
+            assert_eq!(instance.address.as_deref(), Some("mainnet:1442"));
+        }
+    }
+}
@@ -1,10 +1,11 @@
 use async_trait::async_trait;
+use futures_util::future::join_all;
 use pingora::{server::ShutdownWatch, services::background::BackgroundService};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 use tracing::{error, info, warn};
 
-use crate::{Config, State};
+use crate::{config::KupoInstance, Config, State};
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct KupoHealthCheckResponse {
@@ -29,6 +30,17 @@ pub enum ConnectionStatus {
     Disconnected,
 }
 
+/// Ordered from worst to best, so the best upstream of an instance is its `max`.
+#[derive(Serialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamHealth {
+    /// Unreachable, disconnected from the node or still synchronizing, requests fail fast.
+    Unhealthy,
+    /// Connected but lagging behind the node tip, requests are still served.
+    Degraded,
+    Healthy,
+}
+
 pub struct HealthBackgroundService {
     state: Arc<State>,
     config: Arc<Config>,
@@ -38,67 +50,159 @@ impl HealthBackgroundService {
         Self { state, config }
     }
 
-    async fn get_health(&self) -> bool {
-        let Some(kupo_instance) = self
-            .config
-            .kupo_instances
-            .get(&self.config.health_network)
-            .cloned()
-        else {
-            warn!(
-                network = self.config.health_network,
-                "Health network is missing from KUPO_INSTANCES"
-            );
-            return false;
-        };
-
-        let client = match reqwest::Client::builder().build() {
-            Ok(client) => client,
-            Err(err) => {
-                warn!(error = err.to_string(), "Failed to build reqwest client");
-                return false;
-            }
-        };
-
+    async fn get_upstream_health(
+        &self,
+        client: &reqwest::Client,
+        address: &str,
+    ) -> Option<KupoHealthCheckResponse> {
         let response = match client
-            .get(format!("http://{}/health", kupo_instance))
+            .get(format!("http://{}/health", address))
             .header("Accept", "application/json")
             .send()
             .await
         {
             Ok(response) => response,
             Err(err) => {
-                warn!(error = err.to_string(), "Failed to perform health request");
-                return false;
+                warn!(
+                    error = err.to_string(),
+                    upstream = address,
+                    "Failed to perform health request"
+                );
+                return None;
             }
         };
 
         let status = response.status();
         if status.is_client_error() || status.is_server_error() {
-            error!(status = status.to_string(), "Health request failed");
-            return false;
+            error!(
+                status = status.to_string(),
+                upstream = address,
+                "Health request failed"
+            );
+            return None;
         }
 
         let Ok(parsed) = response.json::<KupoHealthCheckResponse>().await else {
-            warn!("Failed to deserialize health check response");
-            return false;
+            warn!(
+                upstream = address,
+                "Failed to deserialize health check response"
+            );
+            return None;
         };
 
-        parsed.connection_status == ConnectionStatus::Connected
+        Some(parsed)
     }
 
-    async fn update_health(&self) {
-        let current_health = *self.state.upstream_health.read().await;
+    fn evaluate_health(&self, address: &str, response: &KupoHealthCheckResponse) -> UpstreamHealth {
+        if response.connection_status != ConnectionStatus::Connected {
+            return UpstreamHealth::Unhealthy;
+        }
 
-        let new_health = self.get_health().await;
+        if let (Some(min), Some(synchronization)) = (
+            self.config.health_min_sync_ratio,
+            response.network_synchronization,
+        ) {
+            if synchronization < min {
+                warn!(
+                    upstream = address,
+                    synchronization, "Upstream is still synchronizing"
+                );
+                return UpstreamHealth::Unhealthy;
+            }
+        }
 
-        match (current_health, new_health) {
-            (false, true) => info!("Upstream is now healthy, ready to proxy requests."),
-            (true, false) => warn!("Upstream is now deamed unhealthy."),
-            _ => {}
+        if let (Some(max), Some(checkpoint), Some(tip)) = (
+            self.config.health_max_checkpoint_gap,
+            response.most_recent_checkpoint,
+            response.most_recent_node_tip,
+        ) {
+            let gap = tip.saturating_sub(checkpoint);
+            if gap > max {
+                warn!(
+                    upstream = address,
+                    gap, "Upstream checkpoint is behind the node tip"
+                );
+                return UpstreamHealth::Degraded;
+            }
+        }
+
+        if let (Some(max), Some(age)) = (
+            self.config.health_max_block_age,
+            response.seconds_since_last_block,
+        ) {
+            if age > max {
+                warn!(upstream = address, age, "Upstream last block is too old");
+                return UpstreamHealth::Degraded;
+            }
+        }
+
+        UpstreamHealth::Healthy
+    }
+
+    /// The health of an instance is the health of its best upstream.
+    async fn get_instance_health(
+        &self,
+        client: &reqwest::Client,
+        instance: &KupoInstance,
+    ) -> UpstreamHealth {
+        let checks = instance.upstreams().into_iter().map(|upstream| async move {
+            match self.get_upstream_health(client, &upstream.address).await {
+                Some(response) => {
+                    self.state.metrics.observe_upstream_health(
+                        instance,
+                        &upstream.address,
+                        &response,
+                    );
+                    self.evaluate_health(&upstream.address, &response)
+                }
+                None => UpstreamHealth::Unhealthy,
+            }
+        });
+
+        join_all(checks)
+            .await
+            .into_iter()
+            .max()
+            .unwrap_or(UpstreamHealth::Unhealthy)
+    }
+
+    async fn update_health(&self) {
+        let client = match reqwest::Client::builder().build() {
+            Ok(client) => client,
+            Err(err) => {
+                warn!(error = err.to_string(), "Failed to build reqwest client");
+                return;
+            }
+        };
+
+        let checks = self.config.kupo_instances.iter().map(|instance| {
+            let client = &client;
+            async move {
+                let health = self.get_instance_health(client, instance).await;
+                (instance.id(), health)
+            }
+        });
+        let new_health: HashMap<String, UpstreamHealth> =
+            join_all(checks).await.into_iter().collect();
+
+        let mut current_health = self.state.upstream_health.write().await;
+        for (instance, health) in new_health.iter() {
+            if current_health.get(instance) == Some(health) {
+                continue;
+            }
+            match health {
+                UpstreamHealth::Healthy => {
+                    info!(
+                        instance,
+                        "Upstream is now healthy, ready to proxy requests."
+                    )
+                }
+                UpstreamHealth::Degraded => warn!(instance, "Upstream is now deamed degraded."),
+                UpstreamHealth::Unhealthy => warn!(instance, "Upstream is now deamed unhealthy."),
+            }
         }
 
-        *self.state.upstream_health.write().await = new_health;
+        *current_health = new_health;
     }
 }
 
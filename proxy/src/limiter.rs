@@ -0,0 +1,795 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use pingora::{server::ShutdownWatch, services::background::BackgroundService};
+use pingora_limits::rate::Rate;
+use redis::{aio::ConnectionManager, AsyncCommands, FromRedisValue, Pipeline};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{OnceCell, OwnedSemaphorePermit, RwLock, Semaphore};
+use tracing::{error, info};
+
+use crate::{config::Config, QuotaPeriod, State, Tier, TierQuota, TierRate};
+
+/// Storage of the tier rate counters.
+#[async_trait]
+pub trait LimiterBackend: Send + Sync {
+    /// Counts `events` for the key on every rate of the tier and returns the usage of each
+    /// window.
+    async fn observe(&self, key: &str, tier: &Tier, events: isize) -> Vec<RateUsage>;
+
+    /// Forgets the counters this replica keeps for a key, eg: when its port is updated.
+    async fn remove(&self, key: &str);
+
+    /// Forgets the counters of a key everywhere, when its port is deleted. Backends that only
+    /// count in memory just `remove` it.
+    async fn delete(&self, key: &str) {
+        self.remove(key).await
+    }
+
+    /// Forgets every counter this replica keeps, eg: when the tiers are reloaded.
+    async fn clear(&self);
+
+    /// Counts `events` for the key on every quota of the tier and returns the usage of each
+    /// period. Quota usage is not affected by `remove` or `clear`.
+    async fn observe_quotas(&self, key: &str, tier: &Tier, events: isize) -> Vec<RateUsage>;
+
+    /// Saves the quota usage so it survives restarts, backends that already store it outside
+    /// of the proxy don't need to do anything.
+    async fn persist(&self) {}
+}
+
+/// Counts in the proxy memory, so limits apply to each proxy replica on its own. Quota usage can
+/// be saved to a file and loaded back on startup.
+#[derive(Default)]
+pub struct MemoryLimiter {
+    counters: RwLock<HashMap<String, Vec<RateCounter>>>,
+    quotas: RwLock<HashMap<String, isize>>,
+    quota_state_path: Option<PathBuf>,
+}
+impl MemoryLimiter {
+    pub fn with_quota_state(path: PathBuf) -> Self {
+        let quotas = match load_quota_state(&path) {
+            Ok(quotas) => quotas,
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "limiter: failed to load quota state"
+                );
+                HashMap::new()
+            }
+        };
+
+        Self {
+            quotas: RwLock::new(quotas),
+            quota_state_path: Some(path),
+            ..Default::default()
+        }
+    }
+
+    async fn has_counters(&self, key: &str) -> bool {
+        self.counters.read().await.contains_key(key)
+    }
+
+    async fn add_counters(&self, key: &str, tier: &Tier) {
+        let rates = tier
+            .rates
+            .iter()
+            .map(|r| RateCounter::new(r.clone()))
+            .collect();
+
+        self.counters.write().await.insert(key.to_string(), rates);
+    }
+}
+
+#[async_trait]
+impl LimiterBackend for MemoryLimiter {
+    async fn observe(&self, key: &str, tier: &Tier, events: isize) -> Vec<RateUsage> {
+        if !self.has_counters(key).await {
+            self.add_counters(key, tier).await;
+        }
+
+        let counters = self.counters.read().await;
+        counters
+            .get(key)
+            .map(|rates| rates.iter().map(|r| r.observe(key, events)).collect())
+            .unwrap_or_default()
+    }
+
+    async fn remove(&self, key: &str) {
+        self.counters.write().await.remove(key);
+    }
+
+    async fn clear(&self) {
+        self.counters.write().await.clear();
+    }
+
+    async fn observe_quotas(&self, key: &str, tier: &Tier, events: isize) -> Vec<RateUsage> {
+        let now = Utc::now();
+        let mut quotas = self.quotas.write().await;
+
+        tier.quotas
+            .iter()
+            .map(|quota| {
+                let (id, start, end) = quota.period.window(now);
+                let count = quotas
+                    .entry(format!("{key}:{}:{id}", quota.period))
+                    .or_default();
+                *count += events;
+                quota_usage(quota, *count, now, start, end)
+            })
+            .collect()
+    }
+
+    async fn persist(&self) {
+        let Some(path) = &self.quota_state_path else {
+            return;
+        };
+
+        // Usage of past periods is not needed anymore.
+        let now = Utc::now();
+        let current: Vec<String> = [QuotaPeriod::Day, QuotaPeriod::Month]
+            .iter()
+            .map(|period| format!(":{period}:{}", period.window(now).0))
+            .collect();
+        let mut quotas = self.quotas.write().await;
+        quotas.retain(|k, _| current.iter().any(|suffix| k.ends_with(suffix)));
+
+        if let Err(err) = save_quota_state(path, &quotas) {
+            error!(
+                error = err.to_string(),
+                "limiter: failed to save quota state"
+            );
+        }
+    }
+}
+
+fn load_quota_state(path: &Path) -> Result<HashMap<String, isize>, Box<dyn Error>> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let contents = fs::read_to_string(path)?;
+    let quotas: HashMap<String, isize> = serde_json::from_str(&contents)?;
+    info!(entries = quotas.len(), "limiter: quota state loaded");
+    Ok(quotas)
+}
+
+fn save_quota_state(path: &Path, quotas: &HashMap<String, isize>) -> Result<(), Box<dyn Error>> {
+    // Write to a temporary file first so a crash never leaves a truncated state behind.
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, serde_json::to_string(quotas)?)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn quota_usage(
+    quota: &TierQuota,
+    count: isize,
+    now: DateTime<Utc>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> RateUsage {
+    RateUsage {
+        limit: quota.limit,
+        count,
+        interval: (end - start).to_std().unwrap_or_default(),
+        reset: (end - now).to_std().unwrap_or_default(),
+    }
+}
+
+/// Counts in a Redis-protocol store shared by every proxy replica, so tier limits are global.
+/// Windows are aligned to the unix epoch so every replica agrees on them. When the store can't
+/// be reached, the in-memory counters are used instead of failing requests.
+pub struct RedisLimiter {
+    client: redis::Client,
+    connection: OnceCell<ConnectionManager>,
+    prefix: String,
+    timeout: Duration,
+    fallback: MemoryLimiter,
+}
+impl RedisLimiter {
+    pub fn new(url: &str, prefix: &str, timeout: Duration) -> Self {
+        let client = redis::Client::open(url).expect("LIMITER_REDIS_URL must be a valid redis url");
+
+        Self {
+            client,
+            connection: OnceCell::new(),
+            prefix: prefix.to_string(),
+            timeout,
+            fallback: MemoryLimiter::default(),
+        }
+    }
+
+    async fn connection(&self) -> redis::RedisResult<ConnectionManager> {
+        self.connection
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await
+            .cloned()
+    }
+
+    /// Runs a pipeline, giving up after `timeout` so a store that doesn't answer can't hang
+    /// requests. That includes connecting, when the store is not connected yet.
+    async fn query<T: FromRedisValue>(&self, pipe: &Pipeline) -> redis::RedisResult<T> {
+        self.with_timeout(async {
+            let mut connection = self.connection().await?;
+            pipe.query_async(&mut connection).await
+        })
+        .await
+    }
+
+    async fn with_timeout<T>(
+        &self,
+        future: impl std::future::Future<Output = redis::RedisResult<T>>,
+    ) -> redis::RedisResult<T> {
+        tokio::time::timeout(self.timeout, future)
+            .await
+            .unwrap_or_else(|_| {
+                Err(redis::RedisError::from((
+                    redis::ErrorKind::IoError,
+                    "shared store timed out",
+                )))
+            })
+    }
+
+    /// Deletes the rate counters matching the pattern, quota usage is kept.
+    async fn delete_shared(&self, pattern: &str) -> redis::RedisResult<()> {
+        let quota_prefix = format!("{}:quota:", self.prefix);
+
+        self.with_timeout(async {
+            let mut connection = self.connection().await?;
+
+            let mut keys: Vec<String> = Vec::new();
+            let mut iter = connection.scan_match::<_, String>(pattern).await?;
+            while let Some(key) = iter.next_item().await {
+                if !key.starts_with(&quota_prefix) {
+                    keys.push(key);
+                }
+            }
+            drop(iter);
+
+            if !keys.is_empty() {
+                connection.del::<_, ()>(keys).await?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn observe_shared(
+        &self,
+        key: &str,
+        tier: &Tier,
+        events: isize,
+    ) -> redis::RedisResult<Vec<RateUsage>> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        let mut usages: Vec<RateUsage> = Vec::new();
+        for rate in tier.rates.iter() {
+            let interval_ms = rate.interval.as_millis().max(1);
+            let window = now.as_millis() / interval_ms;
+            let window_key = format!("{}:{key}:{interval_ms}:{window}", self.prefix);
+
+            pipe.cmd("INCRBY").arg(&window_key).arg(events);
+            pipe.cmd("PEXPIRE")
+                .arg(&window_key)
+                .arg(interval_ms as u64)
+                .ignore();
+
+            usages.push(RateUsage {
+                limit: rate.limit,
+                count: 0,
+                interval: rate.interval,
+                reset: Duration::from_millis((interval_ms - now.as_millis() % interval_ms) as u64),
+            });
+        }
+
+        let counts: Vec<isize> = self.query(&pipe).await?;
+        for (usage, count) in usages.iter_mut().zip(counts) {
+            usage.count = count;
+        }
+
+        Ok(usages)
+    }
+
+    async fn observe_shared_quotas(
+        &self,
+        key: &str,
+        tier: &Tier,
+        events: isize,
+    ) -> redis::RedisResult<Vec<RateUsage>> {
+        let now = Utc::now();
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        let mut windows = Vec::new();
+        for quota in tier.quotas.iter() {
+            let (id, start, end) = quota.period.window(now);
+            let quota_key = format!("{}:quota:{key}:{}:{id}", self.prefix, quota.period);
+
+            pipe.cmd("INCRBY").arg(&quota_key).arg(events);
+            pipe.cmd("EXPIREAT")
+                .arg(&quota_key)
+                .arg(end.timestamp())
+                .ignore();
+
+            windows.push((quota, start, end));
+        }
+
+        let counts: Vec<isize> = self.query(&pipe).await?;
+
+        Ok(windows
+            .into_iter()
+            .zip(counts)
+            .map(|((quota, start, end), count)| quota_usage(quota, count, now, start, end))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl LimiterBackend for RedisLimiter {
+    async fn observe(&self, key: &str, tier: &Tier, events: isize) -> Vec<RateUsage> {
+        match self.observe_shared(key, tier, events).await {
+            Ok(usages) => usages,
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "limiter: shared store failed, using memory counters"
+                );
+                self.fallback.observe(key, tier, events).await
+            }
+        }
+    }
+
+    /// Shared counters are left to expire with their window, every replica updates ports and
+    /// reloads tiers on its own.
+    async fn remove(&self, key: &str) {
+        self.fallback.remove(key).await;
+    }
+
+    async fn delete(&self, key: &str) {
+        self.fallback.remove(key).await;
+
+        let pattern = format!("{}:{key}:*", self.prefix);
+        if let Err(err) = self.delete_shared(&pattern).await {
+            error!(
+                error = err.to_string(),
+                "limiter: failed to remove shared counters"
+            );
+        }
+    }
+
+    async fn clear(&self) {
+        self.fallback.clear().await;
+    }
+
+    async fn observe_quotas(&self, key: &str, tier: &Tier, events: isize) -> Vec<RateUsage> {
+        match self.observe_shared_quotas(key, tier, events).await {
+            Ok(usages) => usages,
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "limiter: shared store failed, using memory quotas"
+                );
+                self.fallback.observe_quotas(key, tier, events).await
+            }
+        }
+    }
+}
+
+/// Counter of a tier rate that also tracks when its window started. It mirrors the lazy reset
+/// of `Rate`, so the time left in the window can be reported to clients.
+pub struct RateCounter {
+    tier_rate: TierRate,
+    rate: Rate,
+    start: Instant,
+    window_start_ms: AtomicU64,
+}
+impl RateCounter {
+    pub fn new(tier_rate: TierRate) -> Self {
+        Self {
+            rate: Rate::new(tier_rate.interval),
+            tier_rate,
+            start: Instant::now(),
+            window_start_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, key: &str, events: isize) -> RateUsage {
+        let interval_ms = self.tier_rate.interval.as_millis() as u64;
+        let now_ms = self.start.elapsed().as_millis() as u64;
+
+        let mut window_start_ms = self.window_start_ms.load(Ordering::Relaxed);
+        if now_ms - window_start_ms >= interval_ms {
+            self.window_start_ms.store(now_ms, Ordering::Relaxed);
+            window_start_ms = now_ms;
+        }
+
+        let count = self.rate.observe(&key, events);
+
+        RateUsage {
+            limit: self.tier_rate.limit,
+            count,
+            interval: self.tier_rate.interval,
+            reset: Duration::from_millis(interval_ms - (now_ms - window_start_ms)),
+        }
+    }
+}
+
+/// Usage of a tier rate window after observing a request.
+#[derive(Debug, Clone)]
+pub struct RateUsage {
+    pub limit: isize,
+    pub count: isize,
+    pub interval: Duration,
+    /// Time left until the window resets.
+    pub reset: Duration,
+}
+impl RateUsage {
+    pub fn remaining(&self) -> isize {
+        (self.limit - self.count).max(0)
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.count > self.limit
+    }
+}
+
+/// Limits the in-flight requests of each consumer in the proxy replica. A permit is held from
+/// the request filter until the request is logged.
+#[derive(Default)]
+pub struct ConcurrencyLimiter {
+    semaphores: RwLock<HashMap<String, ConcurrencySlots>>,
+}
+impl ConcurrencyLimiter {
+    async fn semaphore(&self, key: &str, max: usize) -> Arc<Semaphore> {
+        if let Some(slots) = self.semaphores.read().await.get(key) {
+            if slots.max == max && slots.excess == 0 {
+                return slots.semaphore.clone();
+            }
+        }
+
+        let mut semaphores = self.semaphores.write().await;
+        let slots = semaphores
+            .entry(key.to_string())
+            .or_insert_with(|| ConcurrencySlots {
+                semaphore: Arc::new(Semaphore::new(max)),
+                max,
+                excess: 0,
+            });
+        slots.resize(max);
+        slots.semaphore.clone()
+    }
+
+    /// Waits up to `queue` for a free slot, returns `None` when the consumer is still at its
+    /// limit.
+    pub async fn acquire(
+        &self,
+        key: &str,
+        max: usize,
+        queue: Duration,
+    ) -> Option<OwnedSemaphorePermit> {
+        let semaphore = self.semaphore(key, max).await;
+
+        if queue.is_zero() {
+            return semaphore.try_acquire_owned().ok();
+        }
+
+        tokio::time::timeout(queue, semaphore.acquire_owned())
+            .await
+            .ok()
+            .and_then(Result::ok)
+    }
+
+    /// Forgets the slots of a key, requests in flight release their permit on the old slots.
+    pub async fn remove(&self, key: &str) {
+        self.semaphores.write().await.remove(key);
+    }
+}
+
+/// Slots of a consumer. They are resized when its tier limit changes instead of being replaced,
+/// so requests in flight keep counting against the new limit.
+struct ConcurrencySlots {
+    semaphore: Arc<Semaphore>,
+    max: usize,
+    /// Permits still to forget after a decrease, they are held by requests in flight.
+    excess: usize,
+}
+impl ConcurrencySlots {
+    fn resize(&mut self, max: usize) {
+        if max > self.max {
+            let added = max - self.max;
+            let repaid = added.min(self.excess);
+            self.excess -= repaid;
+            self.semaphore.add_permits(added - repaid);
+        } else {
+            self.excess += self.max - max;
+        }
+        self.max = max;
+
+        if self.excess > 0 {
+            self.excess -= self.semaphore.forget_permits(self.excess);
+        }
+    }
+}
+
+/// Periodically saves the quota usage, and one last time when the proxy shuts down.
+pub struct QuotaBackgroundService {
+    state: Arc<State>,
+    config: Arc<Config>,
+}
+impl QuotaBackgroundService {
+    pub fn new(state: Arc<State>, config: Arc<Config>) -> Self {
+        Self { state, config }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for QuotaBackgroundService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(self.config.proxy_quota_persist_interval) => {
+                    self.state.limiter.persist().await;
+                }
+                _ = shutdown.changed() => {
+                    self.state.limiter.persist().await;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+
+    fn tier() -> Tier {
+        toml::from_str(
+            r#"
+            name = "tier0"
+            [[rates]]
+            interval = "1m"
+            limit = 10
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn limiter(address: &str) -> RedisLimiter {
+        RedisLimiter::new(
+            &format!("redis://{address}"),
+            "test",
+            Duration::from_millis(200),
+        )
+    }
+
+    /// Answers the subset of the Redis protocol used by the limiter, keeping counters in memory.
+    async fn redis_stand_in() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let store: Arc<Mutex<HashMap<String, i64>>> = Arc::default();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve(stream, store.clone()));
+            }
+        });
+
+        address
+    }
+
+    async fn serve(stream: TcpStream, store: Arc<Mutex<HashMap<String, i64>>>) {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut queued: Option<Vec<String>> = None;
+
+        while let Some(args) = read_command(&mut reader).await {
+            let name = args[0].to_ascii_uppercase();
+            let reply = match name.as_str() {
+                "MULTI" => {
+                    queued = Some(Vec::new());
+                    "+OK\r\n".to_string()
+                }
+                "EXEC" => {
+                    let replies = queued.take().unwrap_or_default();
+                    format!("*{}\r\n{}", replies.len(), replies.concat())
+                }
+                _ => {
+                    let reply = run(&args, &store);
+                    match queued.as_mut() {
+                        Some(replies) => {
+                            replies.push(reply);
+                            "+QUEUED\r\n".to_string()
+                        }
+                        None => reply,
+                    }
+                }
+            };
+            if writer.write_all(reply.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    fn run(args: &[String], store: &Mutex<HashMap<String, i64>>) -> String {
+        let mut store = store.lock().unwrap();
+        match args[0].to_ascii_uppercase().as_str() {
+            "INCRBY" => {
+                let count = store.entry(args[1].clone()).or_default();
+                *count += args[2].parse::<i64>().unwrap();
+                format!(":{count}\r\n")
+            }
+            "PEXPIRE" | "EXPIREAT" => ":1\r\n".to_string(),
+            "SCAN" => {
+                let prefix = args[3].trim_end_matches('*');
+                let keys: Vec<String> = store
+                    .keys()
+                    .filter(|key| key.starts_with(prefix))
+                    .map(|key| format!("${}\r\n{key}\r\n", key.len()))
+                    .collect();
+                format!("*2\r\n$1\r\n0\r\n*{}\r\n{}", keys.len(), keys.concat())
+            }
+            "DEL" => {
+                let deleted = args[1..]
+                    .iter()
+                    .filter(|key| store.remove(*key).is_some())
+                    .count();
+                format!(":{deleted}\r\n")
+            }
+            _ => "+OK\r\n".to_string(),
+        }
+    }
+
+    async fn read_command<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Option<Vec<String>> {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await.ok()? == 0 {
+            return None;
+        }
+        let count: usize = line.trim().strip_prefix('*')?.parse().ok()?;
+
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            line.clear();
+            reader.read_line(&mut line).await.ok()?;
+            let len: usize = line.trim().strip_prefix('$')?.parse().ok()?;
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg).await.ok()?;
+            args.push(String::from_utf8_lossy(&arg[..len]).to_string());
+        }
+        Some(args)
+    }
+
+    #[tokio::test]
+    async fn replicas_share_counters() {
+        let address = redis_stand_in().await;
+        let (first, second) = (limiter(&address), limiter(&address));
+
+        first.observe("ns.port", &tier(), 1).await;
+        let usages = second.observe("ns.port", &tier(), 2).await;
+
+        assert_eq!(usages[0].count, 3);
+    }
+
+    #[tokio::test]
+    async fn remove_keeps_shared_counters() {
+        let address = redis_stand_in().await;
+        let limiter = limiter(&address);
+
+        limiter.observe("ns.port", &tier(), 5).await;
+        limiter.remove("ns.port").await;
+
+        assert_eq!(limiter.observe("ns.port", &tier(), 1).await[0].count, 6);
+    }
+
+    #[tokio::test]
+    async fn delete_deletes_shared_counters_of_the_key() {
+        let address = redis_stand_in().await;
+        let limiter = limiter(&address);
+
+        limiter.observe("ns.port", &tier(), 5).await;
+        limiter.observe("ns.other", &tier(), 5).await;
+        limiter.delete("ns.port").await;
+
+        assert_eq!(limiter.observe("ns.port", &tier(), 1).await[0].count, 1);
+        assert_eq!(limiter.observe("ns.other", &tier(), 1).await[0].count, 6);
+    }
+
+    #[tokio::test]
+    async fn clear_keeps_shared_counters() {
+        let address = redis_stand_in().await;
+        let (first, second) = (limiter(&address), limiter(&address));
+
+        first.observe("ns.port", &tier(), 5).await;
+        second.clear().await;
+
+        assert_eq!(second.observe("ns.port", &tier(), 1).await[0].count, 6);
+    }
+
+    #[tokio::test]
+    async fn concurrency_decrease_applies_to_requests_in_flight() {
+        let limiter = ConcurrencyLimiter::default();
+
+        let first = limiter.acquire("ns.port", 2, Duration::ZERO).await;
+        let second = limiter.acquire("ns.port", 2, Duration::ZERO).await;
+        assert!(first.is_some() && second.is_some());
+
+        assert!(limiter
+            .acquire("ns.port", 1, Duration::ZERO)
+            .await
+            .is_none());
+        drop(first);
+        assert!(limiter
+            .acquire("ns.port", 1, Duration::ZERO)
+            .await
+            .is_none());
+        drop(second);
+        let third = limiter.acquire("ns.port", 1, Duration::ZERO).await;
+        assert!(third.is_some());
+        assert!(limiter
+            .acquire("ns.port", 1, Duration::ZERO)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn concurrency_increase_adds_slots() {
+        let limiter = ConcurrencyLimiter::default();
+
+        let first = limiter.acquire("ns.port", 1, Duration::ZERO).await;
+        assert!(first.is_some());
+        assert!(limiter
+            .acquire("ns.port", 1, Duration::ZERO)
+            .await
+            .is_none());
+
+        let second = limiter.acquire("ns.port", 2, Duration::ZERO).await;
+        assert!(second.is_some());
+        assert!(limiter
+            .acquire("ns.port", 2, Duration::ZERO)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_memory_when_the_store_hangs() {
+        // Accepts connections but never answers, like a blackholed store.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                connections.push(stream);
+            }
+        });
+        let limiter = limiter(&address);
+
+        let started = Instant::now();
+        let usages = limiter.observe("ns.port", &tier(), 1).await;
+
+        assert_eq!(usages[0].count, 1);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -1,29 +1,46 @@
+use chrono::{DateTime, Datelike, Months, NaiveTime, Utc};
 use dotenv::dotenv;
-use operator::{kube::ResourceExt, KupoPort};
+use ipnet::IpNet;
+use operator::{
+    build_api_key_hash, kube::ResourceExt, parse_expiration, KupoPort, KupoPortEndpoint,
+    KupoPortKeys, DEFAULT_API_KEY_NAME,
+};
 use pingora::{
+    listeners::TlsSettings,
     server::{configuration::Opt, Server},
     services::background::background_service,
 };
-use pingora_limits::rate::Rate;
-use prometheus::{histogram_opts, opts, register_histogram_vec, register_int_counter_vec};
+use prometheus::{
+    histogram_opts, opts, register_gauge_vec, register_histogram_vec, register_int_counter_vec,
+    register_int_gauge_vec,
+};
 use regex::Regex;
 use serde::{Deserialize, Deserializer};
-use std::{collections::HashMap, fmt::Display, sync::Arc, time::Duration};
+use std::{collections::HashMap, fmt::Display, net::IpAddr, sync::Arc, time::Duration};
 use tokio::sync::RwLock;
-use tracing::Level;
+use tracing::{error, Level};
 
 mod auth;
+mod cache;
 mod config;
 mod health;
+mod limiter;
 mod proxy;
 mod tiers;
+mod tls;
+mod upstream;
 mod utils;
 
 use auth::AuthBackgroundService;
-use config::Config;
-use health::HealthBackgroundService;
+use config::{parse_ip_net, Config, KupoInstance};
+use health::{HealthBackgroundService, KupoHealthCheckResponse, UpstreamHealth};
+use limiter::{
+    ConcurrencyLimiter, LimiterBackend, MemoryLimiter, QuotaBackgroundService, RedisLimiter,
+};
 use proxy::KupoProxy;
 use tiers::TierBackgroundService;
+use tls::{TlsBackgroundService, TlsCertificates};
+use upstream::{build_load_balancer, Upstreams};
 
 use crate::utils::handle_legacy_networks;
 
@@ -33,7 +50,17 @@ fn main() {
     tracing_subscriber::fmt().with_max_level(Level::INFO).init();
 
     let config: Arc<Config> = Arc::default();
-    let state: Arc<State> = Arc::default();
+    let limiter: Box<dyn LimiterBackend> =
+        match (&config.limiter_redis_url, &config.proxy_quota_state_path) {
+            (Some(url), _) => Box::new(RedisLimiter::new(
+                url,
+                &config.limiter_redis_prefix,
+                config.limiter_redis_timeout,
+            )),
+            (None, Some(path)) => Box::new(MemoryLimiter::with_quota_state(path.clone())),
+            (None, None) => Box::new(MemoryLimiter::default()),
+        };
+    let state = Arc::new(State::new(limiter));
 
     let opt = Opt::default();
     let mut server = Server::new(Some(opt)).unwrap();
@@ -51,17 +78,42 @@ fn main() {
     );
     server.add_service(tier_background_service);
 
+    let quota_background_service = background_service(
+        "Quota Persistence Service",
+        QuotaBackgroundService::new(state.clone(), config.clone()),
+    );
+    server.add_service(quota_background_service);
+
+    let certificates = TlsCertificates::load(&config).expect("failed to load tls certificate");
+    let tls_background_service = background_service(
+        "TLS Certificate Service",
+        TlsBackgroundService::new(state.clone(), config.clone(), certificates.clone()),
+    );
+    server.add_service(tls_background_service);
+
+    let mut upstreams = Upstreams::default();
+    for instance in config.kupo_instances.iter() {
+        let load_balancer_service = background_service(
+            &format!("Kupo Load Balancer {}", instance.id()),
+            build_load_balancer(instance, &config),
+        );
+        upstreams.insert(instance, load_balancer_service.task());
+        server.add_service(load_balancer_service);
+    }
+
     let mut kupo_http_proxy = pingora::proxy::http_proxy_service(
         &server.configuration,
-        KupoProxy::new(state.clone(), config.clone()),
+        KupoProxy::new(state.clone(), config.clone(), Arc::new(upstreams)),
     );
-    kupo_http_proxy
-        .add_tls(
-            &config.proxy_addr,
-            &config.ssl_crt_path,
-            &config.ssl_key_path,
-        )
-        .unwrap();
+    // Every listener shares the same service, so they all run the same filters.
+    let mut tls_settings = TlsSettings::with_callbacks(Box::new(certificates.clone())).unwrap();
+    if config.proxy_h2 {
+        tls_settings.enable_h2();
+    }
+    kupo_http_proxy.add_tls_with_settings(&config.proxy_addr, None, tls_settings);
+    if let Some(plain_addr) = &config.proxy_plain_addr {
+        kupo_http_proxy.add_tcp(plain_addr);
+    }
     server.add_service(kupo_http_proxy);
 
     let mut prometheus_service = pingora::services::listening::Service::prometheus_http_service();
@@ -77,17 +129,101 @@ fn main() {
     server.run_forever();
 }
 
-#[derive(Default)]
 pub struct State {
     consumers: RwLock<HashMap<String, Consumer>>,
     tiers: RwLock<HashMap<String, Tier>>,
-    limiter: RwLock<HashMap<String, Vec<(TierRate, Rate)>>>,
+    costs: RwLock<Vec<RouteCost>>,
+    timeouts: RwLock<Vec<RouteTimeouts>>,
+    limiter: Box<dyn LimiterBackend>,
+    concurrency: ConcurrencyLimiter,
     metrics: Metrics,
-    upstream_health: RwLock<bool>,
+    upstream_health: RwLock<HashMap<String, UpstreamHealth>>,
 }
 impl State {
+    pub fn new(limiter: Box<dyn LimiterBackend>) -> Self {
+        Self {
+            consumers: Default::default(),
+            tiers: Default::default(),
+            costs: Default::default(),
+            timeouts: Default::default(),
+            limiter,
+            concurrency: Default::default(),
+            metrics: Default::default(),
+            upstream_health: Default::default(),
+        }
+    }
+
+    /// Consumers are indexed by the SHA-256 of their key. The lookup compares digests, so the
+    /// time it takes reveals nothing about how much of a guessed raw key was right.
     pub async fn get_consumer(&self, key: &str) -> Option<Consumer> {
-        self.consumers.read().await.get(key).cloned()
+        let key_hash = build_api_key_hash(key);
+        self.consumers
+            .read()
+            .await
+            .get(&key_hash)
+            .filter(|consumer| consumer.expires_at.is_none_or(|e| e > Utc::now()))
+            .cloned()
+    }
+
+    /// Replaces every key of a port, so keys dropped by a rotation stop being accepted.
+    pub async fn set_port_consumers(&self, namespace: &str, port_name: &str, keys: ConsumerKeys) {
+        let mut consumers = self.consumers.write().await;
+        consumers.retain(|_, consumer| !consumer.is_port(namespace, port_name));
+        consumers.extend(keys);
+    }
+
+    /// Cost of the first route matching the request, requests without a route cost 1.
+    pub async fn get_request_cost(&self, method: &str, path: &str, query: Option<&str>) -> usize {
+        let params: Vec<&str> = query
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|param| param.split('=').next())
+            .filter(|name| !name.is_empty())
+            .collect();
+
+        self.costs
+            .read()
+            .await
+            .iter()
+            .find(|route| route.matches(method, path, &params))
+            .map_or(1, |route| route.cost)
+    }
+
+    /// Upstream timeouts of the first route matching the request, over the ones of the tier.
+    pub async fn get_upstream_timeouts(
+        &self,
+        tier: &str,
+        method: &str,
+        path: &str,
+    ) -> UpstreamTimeouts {
+        let tier_timeouts = self
+            .tiers
+            .read()
+            .await
+            .get(tier)
+            .map(|tier| tier.timeouts.clone())
+            .unwrap_or_default();
+
+        match self
+            .timeouts
+            .read()
+            .await
+            .iter()
+            .find(|route| route.matches(method, path))
+        {
+            Some(route) => route.timeouts.clone().or(tier_timeouts),
+            None => tier_timeouts,
+        }
+    }
+
+    /// Instances that were not checked yet are assumed healthy. Degraded instances keep serving
+    /// requests, only unhealthy ones are rejected.
+    pub async fn is_upstream_healthy(&self, instance: &KupoInstance) -> bool {
+        self.upstream_health
+            .read()
+            .await
+            .get(&instance.id())
+            .is_none_or(|health| *health != UpstreamHealth::Unhealthy)
     }
 }
 
@@ -96,36 +232,282 @@ pub struct Consumer {
     namespace: String,
     port_name: String,
     tier: String,
-    key: String,
+    key_name: String,
     network: String,
+    pruned: bool,
+    version: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+    endpoint_scopes: Vec<EndpointScope>,
+    allowed_origins: Vec<String>,
+    ip_scope: IpScope,
 }
-impl Display for Consumer {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}.{}", self.namespace, self.port_name)
-    }
-}
-impl From<&KupoPort> for Consumer {
-    fn from(value: &KupoPort) -> Self {
-        let network = handle_legacy_networks(&value.spec.network);
-        let tier = value.spec.throughput_tier.to_string();
-        let key = value.status.as_ref().unwrap().auth_token.clone();
-        let namespace = value.metadata.namespace.as_ref().unwrap().clone();
-        let port_name = value.name_any();
+impl Consumer {
+    pub fn new(port: &KupoPort, key_name: &str) -> Self {
+        let network = handle_legacy_networks(&port.spec.network);
+        let tier = port.spec.throughput_tier.to_string();
+        let namespace = port.metadata.namespace.as_ref().unwrap().clone();
+        let port_name = port.name_any();
+        let pruned = port.spec.prune_utxo;
+        let version = port.spec.kupo_version.clone();
+
+        // Requests must be allowed by the port and by the key used.
+        let mut endpoint_scopes = vec![EndpointScope::new(
+            &port.spec.allowed_endpoints,
+            &port.spec.denied_endpoints,
+        )];
+        if let Some(api_key) = port.spec.api_keys.iter().find(|k| k.name == key_name) {
+            endpoint_scopes.push(EndpointScope::new(
+                &api_key.allowed_endpoints,
+                &api_key.denied_endpoints,
+            ));
+        }
 
         Self {
             namespace,
             port_name,
             tier,
-            key,
+            key_name: key_name.to_string(),
             network,
+            pruned,
+            version,
+            expires_at: None,
+            endpoint_scopes,
+            allowed_origins: port.spec.allowed_origins.clone(),
+            ip_scope: IpScope::new(&port.spec.allowed_ips, &port.spec.denied_ips),
+        }
+    }
+
+    pub fn is_ip_allowed(&self, ip: Option<IpAddr>) -> bool {
+        self.ip_scope.allows(ip)
+    }
+
+    pub fn has_origin_list(&self) -> bool {
+        !self.allowed_origins.is_empty()
+    }
+
+    /// Ports without an origin list can be used from any origin.
+    pub fn is_origin_allowed(&self, origin: &str) -> bool {
+        !self.has_origin_list()
+            || self
+                .allowed_origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    }
+
+    pub fn is_endpoint_allowed(&self, method: &str, path: &str) -> bool {
+        self.endpoint_scopes
+            .iter()
+            .all(|scope| scope.allows(method, path))
+    }
+
+    pub fn is_port(&self, namespace: &str, port_name: &str) -> bool {
+        self.namespace == namespace && self.port_name == port_name
+    }
+
+    /// Limits are shared by every key of a port, so they are tracked by port.
+    pub fn limiter_key(&self) -> String {
+        port_limiter_key(&self.namespace, &self.port_name)
+    }
+
+    /// Consumer table entries of a port, indexed by key hash. Keys replaced by a rotation keep
+    /// their entry until they expire.
+    pub fn keys(port: &KupoPort, hashed_keys: &[HashedKey]) -> ConsumerKeys {
+        hashed_keys
+            .iter()
+            .map(|key| {
+                let consumer = Consumer {
+                    expires_at: key.expires_at,
+                    ..Consumer::new(port, &key.name)
+                };
+                (key.hash.clone(), consumer)
+            })
+            .collect()
+    }
+}
+/// Routes a port or key is restricted to. Denied routes win over allowed ones, and an empty
+/// allow-list allows every route.
+#[derive(Debug, Clone, Default)]
+pub struct EndpointScope {
+    allowed: Vec<EndpointRule>,
+    denied: Vec<EndpointRule>,
+}
+impl EndpointScope {
+    /// An invalid pattern rejects every request instead of widening the scope.
+    pub fn new(allowed: &[KupoPortEndpoint], denied: &[KupoPortEndpoint]) -> Self {
+        let rules = |endpoints: &[KupoPortEndpoint]| {
+            endpoints
+                .iter()
+                .map(EndpointRule::try_from)
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        match (rules(allowed), rules(denied)) {
+            (Ok(allowed), Ok(denied)) => Self { allowed, denied },
+            (Err(err), _) | (_, Err(err)) => {
+                error!(error = err.to_string(), "invalid endpoint pattern");
+                Self {
+                    allowed: vec![],
+                    denied: vec![EndpointRule {
+                        method: None,
+                        path: Regex::new(".*").unwrap(),
+                    }],
+                }
+            }
+        }
+    }
+
+    pub fn allows(&self, method: &str, path: &str) -> bool {
+        (self.allowed.is_empty() || self.allowed.iter().any(|r| r.matches(method, path)))
+            && !self.denied.iter().any(|r| r.matches(method, path))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EndpointRule {
+    method: Option<String>,
+    path: Regex,
+}
+impl EndpointRule {
+    pub fn matches(&self, method: &str, path: &str) -> bool {
+        self.method
+            .as_deref()
+            .is_none_or(|m| m.eq_ignore_ascii_case(method))
+            && self.path.is_match(path)
+    }
+}
+impl TryFrom<&KupoPortEndpoint> for EndpointRule {
+    type Error = regex::Error;
+
+    fn try_from(value: &KupoPortEndpoint) -> Result<Self, Self::Error> {
+        Ok(Self {
+            method: value.method.clone(),
+            path: Regex::new(&value.path)?,
+        })
+    }
+}
+
+/// Client addresses a port can be used from. Denied networks win over allowed ones, and an empty
+/// allow-list allows every address.
+#[derive(Debug, Clone, Default)]
+pub struct IpScope {
+    allowed: Vec<IpNet>,
+    denied: Vec<IpNet>,
+}
+impl IpScope {
+    /// An invalid CIDR rejects every request instead of widening the scope.
+    pub fn new(allowed: &[String], denied: &[String]) -> Self {
+        let networks = |values: &[String]| {
+            values
+                .iter()
+                .map(|value| parse_ip_net(value).ok_or(value))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        match (networks(allowed), networks(denied)) {
+            (Ok(allowed), Ok(denied)) => Self { allowed, denied },
+            (Err(value), _) | (_, Err(value)) => {
+                error!(cidr = value.as_str(), "invalid ip network");
+                let any = ["0.0.0.0/0", "::/0"].map(|net| net.parse().unwrap());
+                Self {
+                    allowed: vec![],
+                    denied: any.to_vec(),
+                }
+            }
+        }
+    }
+
+    /// Clients with an unknown address are only allowed when the scope is not restricted.
+    pub fn allows(&self, ip: Option<IpAddr>) -> bool {
+        if self.allowed.is_empty() && self.denied.is_empty() {
+            return true;
         }
+        let Some(ip) = ip else {
+            return false;
+        };
+
+        (self.allowed.is_empty() || self.allowed.iter().any(|net| net.contains(&ip)))
+            && !self.denied.iter().any(|net| net.contains(&ip))
+    }
+}
+
+pub fn port_limiter_key(namespace: &str, port_name: &str) -> String {
+    format!("{namespace}.{port_name}")
+}
+pub type ConsumerKeys = Vec<(String, Consumer)>;
+
+/// Key of a port as kept by the proxy, raw keys are dropped once the Secret is hashed.
+#[derive(Debug, Clone)]
+pub struct HashedKey {
+    hash: String,
+    name: String,
+    expires_at: Option<DateTime<Utc>>,
+}
+impl HashedKey {
+    pub fn from_port_keys(port_keys: &KupoPortKeys) -> Vec<Self> {
+        let current_keys = port_keys.keys().into_iter().map(|(name, auth_token)| Self {
+            hash: build_api_key_hash(auth_token),
+            name: name.to_string(),
+            expires_at: None,
+        });
+
+        let previous_keys = port_keys
+            .previous_auth_tokens
+            .iter()
+            .filter_map(|previous| {
+                Some(Self {
+                    hash: build_api_key_hash(&previous.auth_token),
+                    name: previous.name.clone(),
+                    expires_at: Some(parse_expiration(&previous.expires_at)?),
+                })
+            });
+
+        current_keys.chain(previous_keys).collect()
+    }
+
+    /// Keys published in the status by operators that don't write Secrets yet, used until the
+    /// port Secret is received.
+    pub fn from_port_status(port: &KupoPort) -> Vec<Self> {
+        let Some(status) = port.status.as_ref() else {
+            return vec![];
+        };
+
+        let current_key = status.auth_token.iter().map(|auth_token| Self {
+            hash: build_api_key_hash(auth_token),
+            name: DEFAULT_API_KEY_NAME.to_string(),
+            expires_at: None,
+        });
+
+        let previous_keys = status.previous_auth_tokens.iter().filter_map(|previous| {
+            Some(Self {
+                hash: build_api_key_hash(previous.auth_token.as_ref()?),
+                name: previous.name.clone(),
+                expires_at: Some(parse_expiration(&previous.expires_at)?),
+            })
+        });
+
+        current_key.chain(previous_keys).collect()
+    }
+}
+impl Display for Consumer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.namespace, self.port_name)
     }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Tier {
     name: String,
+    #[serde(default)]
     rates: Vec<TierRate>,
+    #[serde(default)]
+    quotas: Vec<TierQuota>,
+    /// Maximum in-flight requests of each consumer, unlimited when not set.
+    max_concurrency: Option<usize>,
+    /// How long a request waits for an in-flight slot before being rejected.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    concurrency_queue: Duration,
+    #[serde(flatten)]
+    timeouts: UpstreamTimeouts,
 }
 #[derive(Debug, Clone, Deserialize)]
 pub struct TierRate {
@@ -133,6 +515,125 @@ pub struct TierRate {
     #[serde(deserialize_with = "deserialize_duration")]
     interval: Duration,
 }
+/// Weight charged to the limiter for requests matching a route, so expensive queries consume
+/// more of the tier than cheap ones.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouteCost {
+    method: Option<String>,
+    #[serde(deserialize_with = "deserialize_regex")]
+    path: Regex,
+    /// Query parameters that must be present for the route to match, eg: `unspent`.
+    #[serde(default)]
+    query: Vec<String>,
+    /// Negative costs are rejected when tiers.toml is parsed, they would refund the limits.
+    cost: usize,
+}
+impl RouteCost {
+    pub fn matches(&self, method: &str, path: &str, params: &[&str]) -> bool {
+        self.method
+            .as_deref()
+            .is_none_or(|m| m.eq_ignore_ascii_case(method))
+            && self.path.is_match(path)
+            && self.query.iter().all(|q| params.contains(&q.as_str()))
+    }
+}
+/// Timeouts of the requests to the upstream, unset values keep the pingora defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UpstreamTimeouts {
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    connect_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    read_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    idle_timeout: Option<Duration>,
+    /// Attempts on other upstreams when an idempotent request fails to connect.
+    retries: Option<usize>,
+}
+impl UpstreamTimeouts {
+    /// Values set here win, the missing ones are taken from `fallback`.
+    pub fn or(self, fallback: Self) -> Self {
+        Self {
+            connect_timeout: self.connect_timeout.or(fallback.connect_timeout),
+            read_timeout: self.read_timeout.or(fallback.read_timeout),
+            idle_timeout: self.idle_timeout.or(fallback.idle_timeout),
+            retries: self.retries.or(fallback.retries),
+        }
+    }
+}
+/// Upstream timeouts of requests matching a route, for slow queries that need more time than
+/// the tier allows.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouteTimeouts {
+    method: Option<String>,
+    #[serde(deserialize_with = "deserialize_regex")]
+    path: Regex,
+    #[serde(flatten)]
+    timeouts: UpstreamTimeouts,
+}
+impl RouteTimeouts {
+    pub fn matches(&self, method: &str, path: &str) -> bool {
+        self.method
+            .as_deref()
+            .is_none_or(|m| m.eq_ignore_ascii_case(method))
+            && self.path.is_match(path)
+    }
+}
+pub fn deserialize_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+    let value: String = Deserialize::deserialize(deserializer)?;
+    Regex::new(&value).map_err(<D::Error as serde::de::Error>::custom)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TierQuota {
+    limit: isize,
+    period: QuotaPeriod,
+}
+/// Calendar period of a quota, in UTC.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaPeriod {
+    Day,
+    Month,
+}
+impl QuotaPeriod {
+    /// Returns the id, start and end of the period containing `now`.
+    pub fn window(&self, now: DateTime<Utc>) -> (String, DateTime<Utc>, DateTime<Utc>) {
+        let today = now.date_naive();
+        let (id, start, end) = match self {
+            Self::Day => (
+                today.format("%Y-%m-%d").to_string(),
+                today,
+                today.succ_opt().unwrap(),
+            ),
+            Self::Month => {
+                let start = today.with_day(1).unwrap();
+                let end = start.checked_add_months(Months::new(1)).unwrap();
+                (today.format("%Y-%m").to_string(), start, end)
+            }
+        };
+
+        (
+            id,
+            start.and_time(NaiveTime::MIN).and_utc(),
+            end.and_time(NaiveTime::MIN).and_utc(),
+        )
+    }
+}
+impl Display for QuotaPeriod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Day => write!(f, "day"),
+            Self::Month => write!(f, "month"),
+        }
+    }
+}
+
+pub fn deserialize_optional_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    deserialize_duration(deserializer).map(Some)
+}
+
 pub fn deserialize_duration<'de, D: Deserializer<'de>>(
     deserializer: D,
 ) -> Result<Duration, D::Error> {
@@ -163,13 +664,50 @@ pub fn deserialize_duration<'de, D: Deserializer<'de>>(
 #[derive(Debug, Clone)]
 pub struct Metrics {
     http_total_request: prometheus::IntCounterVec,
+    http_total_cost: prometheus::IntCounterVec,
     http_request_duration_seconds: prometheus::HistogramVec,
+    cache_hits: prometheus::IntCounterVec,
+    cache_misses: prometheus::IntCounterVec,
+    ip_rejections: prometheus::IntCounterVec,
+    upstream_errors: prometheus::IntCounterVec,
+    tls_cert_expiry: prometheus::IntGaugeVec,
+    upstream_most_recent_checkpoint: prometheus::IntGaugeVec,
+    upstream_most_recent_node_tip: prometheus::IntGaugeVec,
+    upstream_checkpoint_lag: prometheus::IntGaugeVec,
+    upstream_seconds_since_last_block: prometheus::IntGaugeVec,
+    upstream_sync_percentage: prometheus::GaugeVec,
+    upstream_info: prometheus::IntGaugeVec,
+    /// Kupo version last published for each instance id and upstream.
+    upstream_versions: Arc<std::sync::Mutex<HashMap<(String, String), String>>>,
 }
 impl Metrics {
     pub fn new() -> Self {
         let http_total_request = register_int_counter_vec!(
             opts!("kupo_proxy_http_total_request", "Total http request",),
-            &["consumer", "namespace", "instance", "status_code", "tier"]
+            &[
+                "consumer",
+                "namespace",
+                "instance",
+                "status_code",
+                "tier",
+                "key"
+            ]
+        )
+        .unwrap();
+
+        let http_total_cost = register_int_counter_vec!(
+            opts!(
+                "kupo_proxy_http_total_cost",
+                "Total cost charged to the limiter by http requests",
+            ),
+            &[
+                "consumer",
+                "namespace",
+                "instance",
+                "status_code",
+                "tier",
+                "key"
+            ]
         )
         .unwrap();
 
@@ -186,9 +724,120 @@ impl Metrics {
         )
         .unwrap();
 
+        let cache_hits = register_int_counter_vec!(
+            opts!(
+                "kupo_proxy_cache_hits_total",
+                "Requests served from the cache",
+            ),
+            &["network"]
+        )
+        .unwrap();
+
+        let cache_misses = register_int_counter_vec!(
+            opts!(
+                "kupo_proxy_cache_misses_total",
+                "Cacheable requests that were fetched from the upstream",
+            ),
+            &["network"]
+        )
+        .unwrap();
+
+        let upstream_errors = register_int_counter_vec!(
+            opts!(
+                "kupo_proxy_upstream_errors_total",
+                "Requests that failed to be proxied to the upstream",
+            ),
+            &["network", "instance", "error"]
+        )
+        .unwrap();
+
+        let ip_rejections = register_int_counter_vec!(
+            opts!(
+                "kupo_proxy_ip_rejected_total",
+                "Requests rejected because of the client address",
+            ),
+            &["consumer", "namespace"]
+        )
+        .unwrap();
+
+        let tls_cert_expiry = register_int_gauge_vec!(
+            opts!(
+                "kupo_proxy_tls_cert_expiry_timestamp_seconds",
+                "Unix timestamp when the served tls certificate expires",
+            ),
+            &["certificate"]
+        )
+        .unwrap();
+
+        let upstream_labels = &["network", "instance", "upstream"];
+
+        let upstream_most_recent_checkpoint = register_int_gauge_vec!(
+            opts!(
+                "kupo_proxy_upstream_most_recent_checkpoint",
+                "Slot of the most recent checkpoint indexed by the upstream",
+            ),
+            upstream_labels
+        )
+        .unwrap();
+
+        let upstream_most_recent_node_tip = register_int_gauge_vec!(
+            opts!(
+                "kupo_proxy_upstream_most_recent_node_tip",
+                "Slot of the node tip seen by the upstream",
+            ),
+            upstream_labels
+        )
+        .unwrap();
+
+        let upstream_checkpoint_lag = register_int_gauge_vec!(
+            opts!(
+                "kupo_proxy_upstream_checkpoint_lag",
+                "Slots between the upstream node tip and its most recent checkpoint",
+            ),
+            upstream_labels
+        )
+        .unwrap();
+
+        let upstream_seconds_since_last_block = register_int_gauge_vec!(
+            opts!(
+                "kupo_proxy_upstream_seconds_since_last_block",
+                "Seconds since the upstream indexed its last block",
+            ),
+            upstream_labels
+        )
+        .unwrap();
+
+        let upstream_sync_percentage = register_gauge_vec!(
+            opts!(
+                "kupo_proxy_upstream_sync_percentage",
+                "Network synchronization of the upstream in percent",
+            ),
+            upstream_labels
+        )
+        .unwrap();
+
+        let upstream_info = register_int_gauge_vec!(
+            opts!("kupo_proxy_upstream_info", "Kupo version of the upstream",),
+            &["network", "instance", "upstream", "version"]
+        )
+        .unwrap();
+
         Self {
             http_total_request,
+            http_total_cost,
             http_request_duration_seconds,
+            cache_hits,
+            cache_misses,
+            ip_rejections,
+            upstream_errors,
+            tls_cert_expiry,
+            upstream_most_recent_checkpoint,
+            upstream_most_recent_node_tip,
+            upstream_checkpoint_lag,
+            upstream_seconds_since_last_block,
+            upstream_sync_percentage,
+            upstream_info,
+            upstream_versions: Default::default(),
         }
     }
 
@@ -198,16 +847,22 @@ impl Metrics {
         namespace: &str,
         instance: &str,
         status: &u16,
+        cost: usize,
     ) {
-        self.http_total_request
-            .with_label_values(&[
-                &consumer.to_string(),
-                namespace,
-                instance,
-                &status.to_string(),
-                &consumer.tier,
-            ])
-            .inc()
+        let consumer_label = consumer.to_string();
+        let status_label = status.to_string();
+        let labels = [
+            consumer_label.as_str(),
+            namespace,
+            instance,
+            status_label.as_str(),
+            consumer.tier.as_str(),
+            consumer.key_name.as_str(),
+        ];
+        self.http_total_request.with_label_values(&labels).inc();
+        self.http_total_cost
+            .with_label_values(&labels)
+            .inc_by(cost as u64);
     }
 
     /// Observe HTTP request duration in seconds.
@@ -221,9 +876,262 @@ impl Metrics {
             .with_label_values(&[&status.to_string(), &consumer.network])
             .observe(duration.as_secs_f64());
     }
+
+    pub fn inc_cache_hit(&self, consumer: &Consumer) {
+        self.cache_hits
+            .with_label_values(&[&consumer.network])
+            .inc()
+    }
+
+    pub fn inc_cache_miss(&self, consumer: &Consumer) {
+        self.cache_misses
+            .with_label_values(&[&consumer.network])
+            .inc()
+    }
+
+    /// Count a failed upstream request, `error` is `timeout`, `connect` or `response`.
+    pub fn inc_upstream_error(&self, consumer: &Consumer, instance: &str, error: &str) {
+        self.upstream_errors
+            .with_label_values(&[&consumer.network, instance, error])
+            .inc()
+    }
+
+    pub fn inc_ip_rejection(&self, consumer: &Consumer) {
+        self.ip_rejections
+            .with_label_values(&[&consumer.to_string(), &consumer.namespace])
+            .inc()
+    }
+
+    pub fn set_tls_cert_expiry(&self, certificate: &str, expires_at: i64) {
+        self.tls_cert_expiry
+            .with_label_values(&[certificate])
+            .set(expires_at)
+    }
+
+    /// Publish the sync state reported by an upstream `/health` endpoint.
+    pub fn observe_upstream_health(
+        &self,
+        instance: &KupoInstance,
+        upstream: &str,
+        response: &KupoHealthCheckResponse,
+    ) {
+        let id = instance.id();
+        let labels = &[instance.network.as_str(), id.as_str(), upstream];
+
+        if let Some(checkpoint) = response.most_recent_checkpoint {
+            self.upstream_most_recent_checkpoint
+                .with_label_values(labels)
+                .set(checkpoint as i64);
+        }
+        if let Some(tip) = response.most_recent_node_tip {
+            self.upstream_most_recent_node_tip
+                .with_label_values(labels)
+                .set(tip as i64);
+        }
+        if let (Some(checkpoint), Some(tip)) = (
+            response.most_recent_checkpoint,
+            response.most_recent_node_tip,
+        ) {
+            self.upstream_checkpoint_lag
+                .with_label_values(labels)
+                .set(tip.saturating_sub(checkpoint) as i64);
+        }
+        if let Some(seconds) = response.seconds_since_last_block {
+            self.upstream_seconds_since_last_block
+                .with_label_values(labels)
+                .set(seconds.into());
+        }
+        if let Some(synchronization) = response.network_synchronization {
+            self.upstream_sync_percentage
+                .with_label_values(labels)
+                .set(synchronization * 100.0);
+        }
+
+        // A Kupo upgrade replaces the version label instead of leaving the previous one behind.
+        let previous = self
+            .upstream_versions
+            .lock()
+            .unwrap()
+            .insert((id.clone(), upstream.to_string()), response.version.clone());
+        if let Some(version) = previous {
+            if version != response.version {
+                let _ = self.upstream_info.remove_label_values(&[
+                    instance.network.as_str(),
+                    id.as_str(),
+                    upstream,
+                    version.as_str(),
+                ]);
+            }
+        }
+        self.upstream_info
+            .with_label_values(&[
+                instance.network.as_str(),
+                id.as_str(),
+                upstream,
+                response.version.as_str(),
+            ])
+            .set(1);
+    }
 }
 impl Default for Metrics {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(y: i32, m: u32, d: u32, h: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, 30, 0).unwrap()
+    }
+
+    #[test]
+    fn day_window_ends_at_next_midnight() {
+        let (id, start, end) = QuotaPeriod::Day.window(at(2024, 2, 28, 23));
+        assert_eq!(id, "2024-02-28");
+        assert_eq!(start, Utc.with_ymd_and_hms(2024, 2, 28, 0, 0, 0).unwrap());
+        assert_eq!(end, Utc.with_ymd_and_hms(2024, 2, 29, 0, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn day_window_across_year_boundary() {
+        let (id, start, end) = QuotaPeriod::Day.window(at(2024, 12, 31, 12));
+        assert_eq!(id, "2024-12-31");
+        assert_eq!(start, Utc.with_ymd_and_hms(2024, 12, 31, 0, 0, 0).unwrap());
+        assert_eq!(end, Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn month_window_across_month_boundary() {
+        let (id, start, end) = QuotaPeriod::Month.window(at(2024, 1, 31, 23));
+        assert_eq!(id, "2024-01");
+        assert_eq!(start, Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap());
+        assert_eq!(end, Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap());
+
+        let (id, start, _) = QuotaPeriod::Month.window(at(2024, 2, 1, 0));
+        assert_eq!(id, "2024-02");
+        assert_eq!(start, end);
+    }
+
+    #[test]
+    fn month_window_across_year_boundary() {
+        let (id, start, end) = QuotaPeriod::Month.window(at(2024, 12, 15, 8));
+        assert_eq!(id, "2024-12");
+        assert_eq!(start, Utc.with_ymd_and_hms(2024, 12, 1, 0, 0, 0).unwrap());
+        assert_eq!(end, Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap());
+    }
+
+    fn route_cost(value: serde_json::Value) -> RouteCost {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn route_cost_requires_every_query_param() {
+        let cost = route_cost(serde_json::json!({
+            "method": "GET",
+            "path": "^/matches/.*$",
+            "query": ["unspent", "order"],
+            "cost": 5
+        }));
+
+        assert!(cost.matches("GET", "/matches/*", &["order", "unspent"]));
+        assert!(cost.matches("get", "/matches/*", &["unspent", "order", "limit"]));
+        assert!(!cost.matches("GET", "/matches/*", &["unspent"]));
+        assert!(!cost.matches("GET", "/matches/*", &[]));
+        assert!(!cost.matches("POST", "/matches/*", &["unspent", "order"]));
+    }
+
+    #[test]
+    fn route_cost_without_query_matches_any_params() {
+        let cost = route_cost(serde_json::json!({ "path": "^/health$", "cost": 0 }));
+
+        assert!(cost.matches("GET", "/health", &[]));
+        assert!(cost.matches("HEAD", "/health", &["unspent"]));
+        assert!(!cost.matches("GET", "/matches/*", &[]));
+    }
+
+    #[test]
+    fn negative_route_cost_is_rejected() {
+        let cost = serde_json::from_value::<RouteCost>(serde_json::json!({
+            "path": "^/matches/.*$",
+            "cost": -1
+        }));
+        assert!(cost.is_err());
+    }
+
+    fn endpoints(value: serde_json::Value) -> Vec<KupoPortEndpoint> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn endpoint_scope_denied_wins_over_allowed() {
+        let scope = EndpointScope::new(
+            &endpoints(serde_json::json!([{ "path": "^/matches/.*$" }])),
+            &endpoints(serde_json::json!([{ "method": "DELETE", "path": "^/matches/.*$" }])),
+        );
+
+        assert!(scope.allows("GET", "/matches/*"));
+        assert!(!scope.allows("delete", "/matches/*"));
+        assert!(!scope.allows("GET", "/scripts/abc"));
+    }
+
+    #[test]
+    fn endpoint_scope_empty_allow_list_allows_every_route() {
+        let scope = EndpointScope::new(
+            &[],
+            &endpoints(serde_json::json!([{ "path": "^/patterns" }])),
+        );
+
+        assert!(scope.allows("GET", "/matches/*"));
+        assert!(scope.allows("POST", "/health"));
+        assert!(!scope.allows("GET", "/patterns"));
+        assert!(EndpointScope::default().allows("PUT", "/patterns/*"));
+    }
+
+    #[test]
+    fn endpoint_scope_invalid_pattern_denies_every_route() {
+        let scope = EndpointScope::new(&endpoints(serde_json::json!([{ "path": "(" }])), &[]);
+
+        assert!(!scope.allows("GET", "/matches/*"));
+        assert!(!scope.allows("GET", "/health"));
+    }
+
+    fn ip(value: &str) -> Option<IpAddr> {
+        Some(value.parse().unwrap())
+    }
+
+    fn networks(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn ip_scope_denied_wins_over_allowed() {
+        let scope = IpScope::new(
+            &networks(&["10.0.0.0/8", "2001:db8::/32"]),
+            &networks(&["10.1.0.0/16"]),
+        );
+
+        assert!(scope.allows(ip("10.2.3.4")));
+        assert!(scope.allows(ip("2001:db8::1")));
+        assert!(!scope.allows(ip("10.1.3.4")));
+        assert!(!scope.allows(ip("198.51.100.1")));
+    }
+
+    #[test]
+    fn ip_scope_unknown_address_is_allowed_only_when_unrestricted() {
+        assert!(IpScope::default().allows(None));
+        assert!(!IpScope::new(&[], &networks(&["10.0.0.0/8"])).allows(None));
+        assert!(!IpScope::new(&networks(&["10.0.0.0/8"]), &[]).allows(None));
+    }
+
+    #[test]
+    fn ip_scope_invalid_network_denies_every_address() {
+        let scope = IpScope::new(&networks(&["not-a-cidr"]), &[]);
+
+        assert!(!scope.allows(ip("10.0.0.1")));
+        assert!(!scope.allows(ip("2001:db8::1")));
+    }
+}
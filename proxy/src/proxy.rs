@@ -1,149 +1,521 @@
 use async_trait::async_trait;
 use bytes::Bytes;
 use pingora::http::{Method, ResponseHeader, StatusCode};
-use pingora::Result;
 use pingora::{
+    cache::{CacheKey, CacheMeta, CachePhase, NoCacheReason, RespCacheable},
+    lb::Backend,
     proxy::{ProxyHttp, Session},
     upstreams::peer::HttpPeer,
 };
-use pingora_limits::rate::Rate;
+use pingora::{Error, ErrorSource, ErrorType, Result};
 use regex::Regex;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::net::IpAddr;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::OwnedSemaphorePermit;
 use tracing::info;
+use uuid::Uuid;
 
-use crate::config::Config;
-use crate::{Consumer, State, Tier};
+use crate::cache::{ResponseCache, COALESCE_FRESHNESS};
+use crate::config::{Config, KupoInstance};
+use crate::health::UpstreamHealth;
+use crate::limiter::RateUsage;
+use crate::upstream::{UpstreamAddress, Upstreams};
+use crate::utils::handle_legacy_networks;
+use crate::{Consumer, State, UpstreamTimeouts};
 
 static DMTR_API_KEY: &str = "dmtr-api-key";
+static DMTR_REQUEST_ID: &str = "dmtr-request-id";
+/// Memory budget of the storage used to coalesce requests when caching is disabled.
+const COALESCE_CACHE_SIZE: usize = 16 * 1024 * 1024;
+/// Host label of the ports of a network, eg: `cardano-mainnet-v2`.
+static NETWORK_HOST_PATTERN: &str = r"^(?<network>[a-z0-9-]+)-(?<version>v\d+)$";
+static EXPOSE_HEADERS: &str = "dmtr-request-id, RateLimit-Limit, RateLimit-Remaining, RateLimit-Reset, RateLimit-Policy, Retry-After";
+
+/// Errors answered by the proxy itself, serialized as a JSON envelope with a stable `code` so
+/// clients can tell them apart.
+#[derive(Debug)]
+pub enum ProxyError {
+    PrivateEndpoint,
+    InvalidApiKey,
+    EndpointNotAllowed,
+    OriginNotAllowed,
+    IpNotAllowed,
+    NetworkMismatch(String),
+    NetworkNotConfigured(String),
+    UpstreamUnavailable(String),
+    UpstreamTimeout(String),
+    UpstreamError(String),
+    RateLimitExceeded,
+    QuotaExceeded,
+    ConcurrencyLimitExceeded,
+}
+impl ProxyError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::PrivateEndpoint | Self::InvalidApiKey => StatusCode::UNAUTHORIZED,
+            Self::EndpointNotAllowed | Self::OriginNotAllowed | Self::IpNotAllowed => {
+                StatusCode::FORBIDDEN
+            }
+            Self::NetworkMismatch(_) => StatusCode::MISDIRECTED_REQUEST,
+            Self::NetworkNotConfigured(_) | Self::UpstreamError(_) => StatusCode::BAD_GATEWAY,
+            Self::UpstreamUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::UpstreamTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::RateLimitExceeded | Self::QuotaExceeded | Self::ConcurrencyLimitExceeded => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::PrivateEndpoint => "private_endpoint",
+            Self::InvalidApiKey => "invalid_api_key",
+            Self::EndpointNotAllowed => "endpoint_not_allowed",
+            Self::OriginNotAllowed => "origin_not_allowed",
+            Self::IpNotAllowed => "ip_not_allowed",
+            Self::NetworkMismatch(_) => "network_mismatch",
+            Self::NetworkNotConfigured(_) => "network_not_configured",
+            Self::UpstreamUnavailable(_) => "upstream_unavailable",
+            Self::UpstreamTimeout(_) => "upstream_timeout",
+            Self::UpstreamError(_) => "upstream_error",
+            Self::RateLimitExceeded => "rate_limit_exceeded",
+            Self::QuotaExceeded => "quota_exceeded",
+            Self::ConcurrencyLimitExceeded => "concurrency_limit_exceeded",
+        }
+    }
+}
+impl Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PrivateEndpoint => write!(f, "unauthorized to request the endpoint"),
+            Self::InvalidApiKey => write!(f, "the api key is missing or invalid"),
+            Self::EndpointNotAllowed => {
+                write!(f, "the api key is not allowed to request the endpoint")
+            }
+            Self::OriginNotAllowed => write!(f, "the api key can't be used from this origin"),
+            Self::IpNotAllowed => write!(f, "the api key can't be used from this address"),
+            Self::NetworkMismatch(host) => {
+                write!(
+                    f,
+                    "the api key is not valid for {host}, use the hostname of its network"
+                )
+            }
+            Self::NetworkNotConfigured(network) => {
+                write!(f, "no upstream is configured for {network}")
+            }
+            Self::UpstreamUnavailable(network) => {
+                write!(f, "upstream for {network} is unavailable")
+            }
+            Self::UpstreamTimeout(network) => {
+                write!(f, "upstream for {network} took too long to respond")
+            }
+            Self::UpstreamError(network) => {
+                write!(f, "upstream for {network} failed to respond")
+            }
+            Self::RateLimitExceeded => write!(f, "rate limit of the tier exceeded"),
+            Self::QuotaExceeded => write!(f, "request quota of the tier exhausted"),
+            Self::ConcurrencyLimitExceeded => {
+                write!(f, "too many requests in flight for the tier")
+            }
+        }
+    }
+}
 
 pub struct KupoProxy {
     state: Arc<State>,
     config: Arc<Config>,
+    upstreams: Arc<Upstreams>,
+    cache: Option<ResponseCache>,
     host_regex: Regex,
+    network_host_regex: Regex,
     private_endpoint_regex: Regex,
 }
 impl KupoProxy {
-    pub fn new(state: Arc<State>, config: Arc<Config>) -> Self {
+    pub fn new(state: Arc<State>, config: Arc<Config>, upstreams: Arc<Upstreams>) -> Self {
         let host_regex = Regex::new(r"([dmtr_]?[\w\d-]+)?\.?.+").unwrap();
+        let network_host_regex = Regex::new(NETWORK_HOST_PATTERN).unwrap();
         let private_endpoint_regex = Regex::new(&config.private_endpoint).unwrap();
+        // Coalescing shares responses through the cache storage, so it needs one even when
+        // caching is disabled.
+        let cache = match (config.cache_max_size, config.coalesce_timeout) {
+            (Some(max_size), coalesce_timeout) => Some(ResponseCache::new(
+                max_size,
+                config.cache_rules.clone(),
+                coalesce_timeout,
+            )),
+            (None, Some(coalesce_timeout)) => Some(ResponseCache::new(
+                COALESCE_CACHE_SIZE,
+                Vec::new(),
+                Some(coalesce_timeout),
+            )),
+            (None, None) => None,
+        };
 
         Self {
             state,
             config,
+            upstreams,
+            cache,
             host_regex,
+            network_host_regex,
             private_endpoint_regex,
         }
     }
 
-    async fn has_limiter(&self, consumer: &Consumer) -> bool {
-        let rate_limiter_map = self.state.limiter.read().await;
-        rate_limiter_map.get(&consumer.key).is_some()
-    }
-
-    async fn add_limiter(&self, consumer: &Consumer, tier: &Tier) {
-        let rates = tier
-            .rates
-            .iter()
-            .map(|r| (r.clone(), Rate::new(r.interval)))
-            .collect();
-
-        self.state
-            .limiter
-            .write()
+    async fn limiter(&self, ctx: &mut Context) -> Result<Option<ProxyError>> {
+        let tier = self
+            .state
+            .tiers
+            .read()
             .await
-            .insert(consumer.key.clone(), rates);
-    }
+            .get(&ctx.consumer.tier)
+            .cloned();
+        let Some(tier) = tier else {
+            return Ok(Some(ProxyError::RateLimitExceeded));
+        };
+        let cost = isize::try_from(ctx.cost).unwrap_or(isize::MAX);
 
-    async fn limiter(&self, consumer: &Consumer) -> Result<bool> {
-        let tiers = self.state.tiers.read().await.clone();
-        let tier = tiers.get(&consumer.tier);
-        if tier.is_none() {
-            return Ok(true);
+        ctx.rate_limits = self
+            .state
+            .limiter
+            .observe(&ctx.consumer.limiter_key(), &tier, cost)
+            .await;
+        if ctx.rate_limits.iter().any(RateUsage::exceeded) {
+            return Ok(Some(ProxyError::RateLimitExceeded));
         }
-        let tier = tier.unwrap();
 
-        if !self.has_limiter(consumer).await {
-            self.add_limiter(consumer, tier).await;
+        if let Some(max) = tier.max_concurrency {
+            ctx.concurrency_permit = self
+                .state
+                .concurrency
+                .acquire(&ctx.consumer.limiter_key(), max, tier.concurrency_queue)
+                .await;
+            if ctx.concurrency_permit.is_none() {
+                return Ok(Some(ProxyError::ConcurrencyLimitExceeded));
+            }
         }
 
-        let rate_limiter_map = self.state.limiter.read().await;
-        let rates = rate_limiter_map.get(&consumer.key).unwrap();
-
-        if rates
-            .iter()
-            .any(|(t, r)| r.observe(&consumer.key, 1) > t.limit)
-        {
-            return Ok(true);
+        // Requests rejected by the rates or concurrency don't consume the quota.
+        let quotas = self
+            .state
+            .limiter
+            .observe_quotas(&ctx.consumer.limiter_key(), &tier, cost)
+            .await;
+        let exceeded = quotas.iter().any(RateUsage::exceeded);
+        ctx.rate_limits.extend(quotas);
+        if exceeded {
+            return Ok(Some(ProxyError::QuotaExceeded));
         }
 
-        Ok(false)
+        Ok(None)
     }
 
     async fn respond_health(&self, session: &mut Session, ctx: &mut Context) {
         ctx.is_health_request = true;
         session.set_keepalive(None);
 
-        let is_healthy = *self.state.upstream_health.read().await;
-        let (code, message) = if is_healthy {
-            (200, "OK")
-        } else {
-            (500, "UNHEALTHY")
-        };
+        let upstream_health = self.state.upstream_health.read().await.clone();
+
+        let mut networks: BTreeMap<&str, (UpstreamHealth, BTreeMap<String, UpstreamHealth>)> =
+            BTreeMap::new();
+        for instance in self.config.kupo_instances.iter() {
+            let id = instance.id();
+            let Some(health) = upstream_health.get(&id).copied() else {
+                continue;
+            };
+            let network = networks
+                .entry(&instance.network)
+                .or_insert((UpstreamHealth::Unhealthy, BTreeMap::new()));
+            network.0 = network.0.max(health);
+            network.1.insert(id, health);
+        }
 
-        let header = Box::new(ResponseHeader::build(code, None).unwrap());
-        session.write_response_header(header, true).await.unwrap();
+        // The status code follows the health network, so one network going down doesn't take
+        // the proxy out of the load balancer.
+        let is_healthy = networks
+            .get(self.config.health_network.as_str())
+            .is_some_and(|(health, _)| *health != UpstreamHealth::Unhealthy);
+        let networks: BTreeMap<&str, Value> = networks
+            .into_iter()
+            .map(|(network, (status, instances))| {
+                (network, json!({ "status": status, "instances": instances }))
+            })
+            .collect();
+        let code = if is_healthy { 200 } else { 500 };
+        let body = json!({ "healthy": is_healthy, "networks": networks });
+
+        let mut header = Box::new(ResponseHeader::build(code, None).unwrap());
+        header
+            .insert_header("Content-Type", "application/json")
+            .unwrap();
+        session.write_response_header(header, false).await.unwrap();
         session
-            .write_response_body(Some(Bytes::from(message)), true)
+            .write_response_body(Some(Bytes::from(body.to_string())), true)
             .await
             .unwrap();
     }
 
-    async fn respond_unauthorized(&self, session: &mut Session) {
+    async fn respond_error(&self, session: &mut Session, ctx: &Context, error: ProxyError) {
         session.set_keepalive(None);
 
-        let header = Box::new(ResponseHeader::build(StatusCode::UNAUTHORIZED, None).unwrap());
-        session.write_response_header(header, true).await.unwrap();
+        let body = json!({
+            "error": {
+                "code": error.code(),
+                "message": error.to_string(),
+                "request_id": ctx.request_id,
+            }
+        });
+
+        let mut header = Box::new(ResponseHeader::build(error.status(), None).unwrap());
+        header
+            .insert_header("Content-Type", "application/json")
+            .unwrap();
+        header
+            .insert_header(DMTR_REQUEST_ID, &ctx.request_id)
+            .unwrap();
+        KupoProxy::add_cors_headers(&mut header, &self.config, ctx.cors_origin.as_deref()).unwrap();
+        KupoProxy::add_rate_limit_headers(&mut header, &ctx.rate_limits).unwrap();
+        session.write_response_header(header, false).await.unwrap();
         session
-            .write_response_body(
-                Some(Bytes::from("unauthorized to request the endpoint")),
-                true,
-            )
+            .write_response_body(Some(Bytes::from(body.to_string())), true)
             .await
             .unwrap();
     }
 
-    async fn respond_options(&self, session: &mut Session) {
-        let mut header = Box::new(ResponseHeader::build(StatusCode::NO_CONTENT, None).unwrap());
-        KupoProxy::add_cors_headers(&mut header, &self.config).unwrap();
+    /// Preflights of ports with an origin list are only answered with CORS headers when the
+    /// origin is on the list, so browsers block the request otherwise.
+    async fn respond_options(&self, session: &mut Session, ctx: &mut Context) {
+        let key = self.request_key(session);
+        let origin = request_origin(session);
+
+        let status = match (self.state.get_consumer(&key).await, origin) {
+            (Some(consumer), Some(origin)) if consumer.has_origin_list() => {
+                if consumer.is_origin_allowed(&origin) {
+                    ctx.cors_origin = Some(origin);
+                    StatusCode::NO_CONTENT
+                } else {
+                    StatusCode::FORBIDDEN
+                }
+            }
+            _ => StatusCode::NO_CONTENT,
+        };
+
+        let mut header = Box::new(ResponseHeader::build(status, None).unwrap());
+        if status == StatusCode::NO_CONTENT {
+            KupoProxy::add_cors_headers(&mut header, &self.config, ctx.cors_origin.as_deref())
+                .unwrap();
+        }
         session.write_response_header(header, true).await.unwrap();
     }
 
-    fn upstream_instance(&self, network: &str) -> Option<&str> {
-        self.config.kupo_instances.get(network).map(String::as_str)
+    /// Network and version of the `{network}-{version}.{subdomain}.{zone}` hostname the request
+    /// was sent to. Hosts that don't follow it, like internal service names, return `None`.
+    fn host_network(&self, session: &Session) -> Option<(String, String)> {
+        parse_host_network(&self.network_host_regex, &request_host(session)?)
+    }
+
+    /// Address of the socket peer, or the client it forwards the request for when the peer is a
+    /// trusted proxy.
+    fn client_ip(&self, session: &Session) -> Option<IpAddr> {
+        let peer = session.client_addr()?.as_inet()?.ip();
+
+        let hops: Vec<&str> = session
+            .req_header()
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect();
+
+        Some(forwarded_client_ip(peer, &hops, |ip| {
+            self.config.is_trusted_proxy(ip)
+        }))
+    }
+
+    /// The key is read from the `dmtr-api-key` header, or from the first label of the host.
+    fn request_key(&self, session: &Session) -> String {
+        // A request without a key resolves to no consumer and gets the invalid key error.
+        let host = request_host(session).unwrap_or_default();
+        let captures = self.host_regex.captures(&host);
+        session
+            .get_header(DMTR_API_KEY)
+            .and_then(|v| v.to_str().ok())
+            .or_else(|| captures.as_ref()?.get(1).map(|v| v.as_str()))
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    fn upstream_instance(&self, consumer: &Consumer) -> Option<&KupoInstance> {
+        let version = consumer
+            .version
+            .as_deref()
+            .unwrap_or(&self.config.default_kupo_version);
+
+        self.config
+            .kupo_instance(&consumer.network, consumer.pruned, version)
     }
 
-    fn add_cors_headers(resp: &mut ResponseHeader, config: &Config) -> Result<()> {
-        resp.insert_header("Access-Control-Allow-Origin", &config.cors_allow_origin)?;
+    /// Ports with an origin list get the request origin echoed back, others the global
+    /// `CORS_ALLOW_ORIGIN`.
+    fn add_cors_headers(
+        resp: &mut ResponseHeader,
+        config: &Config,
+        cors_origin: Option<&str>,
+    ) -> Result<()> {
+        match cors_origin {
+            Some(origin) => {
+                resp.insert_header("Access-Control-Allow-Origin", origin)?;
+                resp.insert_header("Vary", "Origin")?;
+            }
+            None => {
+                resp.insert_header("Access-Control-Allow-Origin", &config.cors_allow_origin)?;
+            }
+        }
         resp.insert_header("Access-Control-Allow-Methods", &config.cors_allow_methods)?;
         resp.insert_header("Access-Control-Allow-Headers", &config.cors_allow_headers)?;
+        resp.insert_header("Access-Control-Expose-Headers", EXPOSE_HEADERS)?;
         resp.insert_header("Access-Control-Max-Age", &config.cors_max_age)
     }
+
+    /// Reports the window closest to its limit, following the IETF RateLimit header fields
+    /// draft, and when to retry if any window was exceeded.
+    fn add_rate_limit_headers(resp: &mut ResponseHeader, rate_limits: &[RateUsage]) -> Result<()> {
+        let Some(usage) = rate_limits.iter().min_by_key(|u| u.remaining()) else {
+            return Ok(());
+        };
+
+        let policy = rate_limits
+            .iter()
+            .map(|u| format!("{};w={}", u.limit, u.interval.as_secs()))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        resp.insert_header("RateLimit-Limit", usage.limit.to_string())?;
+        resp.insert_header("RateLimit-Remaining", usage.remaining().to_string())?;
+        resp.insert_header("RateLimit-Reset", ceil_secs(usage.reset).to_string())?;
+        resp.insert_header("RateLimit-Policy", policy)?;
+
+        if let Some(retry_after) = rate_limits
+            .iter()
+            .filter(|u| u.exceeded())
+            .map(|u| u.reset)
+            .max()
+        {
+            resp.insert_header("Retry-After", ceil_secs(retry_after).to_string())?;
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct Context {
     is_health_request: bool,
     instance: String,
+    instance_id: String,
+    backend: Option<Backend>,
     consumer: Consumer,
     start_time: Option<Instant>,
+    request_id: String,
+    rate_limits: Vec<RateUsage>,
+    cost: usize,
+    concurrency_permit: Option<OwnedSemaphorePermit>,
+    cache_ttl: Option<Duration>,
+    /// The route has no cache rule, its response is only shared with requests in flight.
+    coalesce_only: bool,
+    request_time: Option<SystemTime>,
+    cors_origin: Option<String>,
+    timeouts: UpstreamTimeouts,
+    retries: usize,
+    /// Upstreams that refused the request, skipped by the retries.
+    failed_backends: Vec<Backend>,
+}
+
+fn request_origin(session: &Session) -> Option<String> {
+    session
+        .get_header("origin")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Hostname the request was sent to, without the port. HTTP/2 clients send it in the
+/// `:authority` pseudo header instead of `Host`.
+fn request_host(session: &Session) -> Option<String> {
+    let host = match session.get_header("host") {
+        Some(host) => host.to_str().ok()?.split(':').next()?.to_string(),
+        None => session.req_header().uri.authority()?.host().to_string(),
+    };
+    Some(host)
+}
+
+/// Network and version of the first host label following `{network}-{version}`, legacy network
+/// names are mapped to the current ones. Hostnames are case insensitive.
+fn parse_host_network(network_host_regex: &Regex, host: &str) -> Option<(String, String)> {
+    host.to_ascii_lowercase().split('.').find_map(|label| {
+        let captures = network_host_regex.captures(label)?;
+        Some((
+            handle_legacy_networks(&captures["network"]),
+            captures["version"].to_string(),
+        ))
+    })
+}
+
+/// A key can only be used on the hostname of its network and version. Hosts without a network
+/// label are not checked.
+fn check_host_network(
+    host_network: Option<(String, String)>,
+    network: &str,
+    version: &str,
+) -> Result<(), ProxyError> {
+    match host_network {
+        Some((host_network, host_version))
+            if host_network != network || host_version != version =>
+        {
+            Err(ProxyError::NetworkMismatch(format!(
+                "{host_network}-{host_version}"
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The `X-Forwarded-For` hops are walked from the closest one while the address they were
+/// received from is a trusted proxy, so the first hop that isn't a trusted proxy is the client.
+fn forwarded_client_ip(
+    peer: IpAddr,
+    hops: &[&str],
+    is_trusted_proxy: impl Fn(IpAddr) -> bool,
+) -> IpAddr {
+    let mut ip = peer;
+    for hop in hops.iter().rev() {
+        if !is_trusted_proxy(ip) {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(hop) => ip = hop,
+            Err(_) => break,
+        }
+    }
+    ip
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_millis().div_ceil(1000) as u64
 }
 
 #[async_trait]
 impl ProxyHttp for KupoProxy {
     type CTX = Context;
     fn new_ctx(&self) -> Self::CTX {
-        Context::default()
+        Context {
+            request_id: Uuid::new_v4().to_string(),
+            ..Default::default()
+        }
     }
 
     async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool>
@@ -151,6 +523,7 @@ impl ProxyHttp for KupoProxy {
         Self::CTX: Send + Sync,
     {
         ctx.start_time = Some(Instant::now());
+        ctx.request_time = Some(SystemTime::now());
         let state = self.state.clone();
 
         // Check if the request is going to the health endpoint before continuing.
@@ -162,42 +535,102 @@ impl ProxyHttp for KupoProxy {
 
         let pattern = format!("{}{path}", &session.req_header().method);
         if self.private_endpoint_regex.is_match(&pattern) {
-            self.respond_unauthorized(session).await;
+            self.respond_error(session, ctx, ProxyError::PrivateEndpoint)
+                .await;
             return Ok(true);
         }
 
         if session.req_header().method == Method::OPTIONS {
-            self.respond_options(session).await;
+            self.respond_options(session, ctx).await;
             return Ok(true);
         }
 
-        // Extract key from host or header.
-        let host = session
-            .get_header("host")
-            .map(|v| v.to_str().unwrap())
-            .unwrap();
-        let captures = self.host_regex.captures(host).unwrap();
-        let key = session
-            .get_header(DMTR_API_KEY)
-            .and_then(|v| v.to_str().ok())
-            .or_else(|| captures.get(1).map(|v| v.as_str()))
-            .unwrap_or_default();
+        let key = self.request_key(session);
+        let Some(consumer) = state.get_consumer(&key).await else {
+            self.respond_error(session, ctx, ProxyError::InvalidApiKey)
+                .await;
+            return Ok(true);
+        };
+
+        // Only browsers send an origin, other clients aren't restricted by the origin list.
+        if let Some(origin) = request_origin(session) {
+            if !consumer.is_origin_allowed(&origin) {
+                self.respond_error(session, ctx, ProxyError::OriginNotAllowed)
+                    .await;
+                return Ok(true);
+            }
+            if consumer.has_origin_list() {
+                ctx.cors_origin = Some(origin);
+            }
+        }
+
+        // Keys only work on the hostname of their network and version, instead of silently
+        // answering with the data of another chain.
+        let consumer_version = consumer
+            .version
+            .as_deref()
+            .unwrap_or(&self.config.default_kupo_version);
+        let host_network = self.host_network(session);
+        if let Err(error) = check_host_network(host_network, &consumer.network, consumer_version) {
+            self.respond_error(session, ctx, error).await;
+            return Ok(true);
+        }
+
+        if !consumer.is_ip_allowed(self.client_ip(session)) {
+            state.metrics.inc_ip_rejection(&consumer);
+            self.respond_error(session, ctx, ProxyError::IpNotAllowed)
+                .await;
+            return Ok(true);
+        }
 
-        let Some(consumer) = state.get_consumer(key).await else {
-            session.respond_error(401).await?;
+        let req = session.req_header();
+        if !consumer.is_endpoint_allowed(req.method.as_str(), req.uri.path()) {
+            self.respond_error(session, ctx, ProxyError::EndpointNotAllowed)
+                .await;
+            return Ok(true);
+        }
+
+        let Some(instance) = self.upstream_instance(&consumer) else {
+            let error = ProxyError::NetworkNotConfigured(consumer.network.clone());
+            self.respond_error(session, ctx, error).await;
             return Ok(true);
         };
 
-        let Some(instance) = self.upstream_instance(&consumer.network) else {
-            session.respond_error(502).await?;
+        if !state.is_upstream_healthy(instance).await {
+            let error = ProxyError::UpstreamUnavailable(consumer.network.clone());
+            self.respond_error(session, ctx, error).await;
+            return Ok(true);
+        }
+
+        let Some(backend) = self
+            .upstreams
+            .select(instance, consumer.limiter_key().as_bytes())
+        else {
+            let error = ProxyError::UpstreamUnavailable(consumer.network.clone());
+            self.respond_error(session, ctx, error).await;
             return Ok(true);
         };
 
-        ctx.consumer = consumer;
-        ctx.instance = instance.to_string();
+        let req = session.req_header();
+        ctx.timeouts = state
+            .get_upstream_timeouts(&consumer.tier, req.method.as_str(), req.uri.path())
+            .await;
 
-        if self.limiter(&ctx.consumer).await? {
-            session.respond_error(429).await?;
+        ctx.consumer = consumer;
+        ctx.instance_id = instance.id();
+        ctx.instance = backend
+            .ext
+            .get::<UpstreamAddress>()
+            .map_or(backend.addr.to_string(), |u| u.0.clone());
+        ctx.backend = Some(backend);
+
+        let req = session.req_header();
+        ctx.cost = state
+            .get_request_cost(req.method.as_str(), req.uri.path(), req.uri.query())
+            .await;
+
+        if let Some(error) = self.limiter(ctx).await? {
+            self.respond_error(session, ctx, error).await;
             return Ok(true);
         }
 
@@ -208,40 +641,231 @@ impl ProxyHttp for KupoProxy {
         &self,
         _session: &mut Session,
         upstream_response: &mut ResponseHeader,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> Result<()>
     where
         Self::CTX: Send + Sync,
     {
-        KupoProxy::add_cors_headers(upstream_response, &self.config)?;
+        KupoProxy::add_cors_headers(upstream_response, &self.config, ctx.cors_origin.as_deref())?;
+        upstream_response.insert_header(DMTR_REQUEST_ID, &ctx.request_id)?;
+        KupoProxy::add_rate_limit_headers(upstream_response, &ctx.rate_limits)?;
         Ok(())
     }
 
+    fn request_cache_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<()> {
+        let Some(cache) = &self.cache else {
+            return Ok(());
+        };
+        if session.req_header().method != Method::GET {
+            return Ok(());
+        }
+        let ttl = match cache.ttl(session.req_header().uri.path()) {
+            Some(ttl) => ttl,
+            None if cache.coalesces() => {
+                ctx.coalesce_only = true;
+                COALESCE_FRESHNESS
+            }
+            None => return Ok(()),
+        };
+
+        ctx.cache_ttl = Some(ttl);
+        session
+            .cache
+            .enable(cache.storage, Some(cache.eviction), None, cache.lock);
+        Ok(())
+    }
+
+    /// Responses are cached and coalesced per routing entry, since each one serves a different
+    /// network, prune flag or Kupo version.
+    fn cache_key_callback(&self, session: &Session, ctx: &mut Self::CTX) -> Result<CacheKey> {
+        let uri = &session.req_header().uri;
+        let primary = uri
+            .path_and_query()
+            .map_or(uri.path(), |path_and_query| path_and_query.as_str());
+
+        Ok(CacheKey::new(
+            ctx.instance_id.clone(),
+            primary.to_string(),
+            "",
+        ))
+    }
+
+    /// Coalesced responses are only shared with the requests that arrived before they were
+    /// fetched, anything stored is treated as expired for later requests.
+    async fn cache_hit_filter(
+        &self,
+        _session: &Session,
+        meta: &CacheMeta,
+        ctx: &mut Self::CTX,
+    ) -> Result<bool>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if !ctx.coalesce_only {
+            return Ok(false);
+        }
+        Ok(ctx
+            .request_time
+            .is_none_or(|request_time| meta.created() <= request_time))
+    }
+
+    fn response_cache_filter(
+        &self,
+        _session: &Session,
+        resp: &ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<RespCacheable> {
+        let Some(ttl) = ctx.cache_ttl else {
+            return Ok(RespCacheable::Uncacheable(NoCacheReason::NeverEnabled));
+        };
+        if resp.status != StatusCode::OK {
+            return Ok(RespCacheable::Uncacheable(NoCacheReason::OriginNotCache));
+        }
+
+        let now = SystemTime::now();
+        Ok(RespCacheable::Cacheable(CacheMeta::new(
+            now + ttl,
+            now,
+            0,
+            0,
+            resp.clone(),
+        )))
+    }
+
     async fn upstream_peer(
         &self,
         _session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>> {
-        let http_peer = HttpPeer::new(&ctx.instance, false, String::default());
+        let backend = ctx.backend.clone().unwrap();
+        let mut http_peer = HttpPeer::new(backend, false, String::default());
+        http_peer.options.connection_timeout = ctx.timeouts.connect_timeout;
+        http_peer.options.read_timeout = ctx.timeouts.read_timeout;
+        http_peer.options.idle_timeout = ctx.timeouts.idle_timeout;
+        if self.config.upstream_h2c {
+            http_peer.options.set_http_version(2, 2);
+        }
         Ok(Box::new(http_peer))
     }
 
+    /// Only idempotent requests are retried, only when nothing reached the upstream, and only
+    /// on a healthy upstream of the instance that didn't fail the request yet.
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<Error>,
+    ) -> Box<Error> {
+        let method = &session.req_header().method;
+        let idempotent = *method == Method::GET || *method == Method::HEAD;
+        if !idempotent || ctx.retries >= ctx.timeouts.retries.unwrap_or_default() {
+            return e;
+        }
+
+        ctx.failed_backends.extend(ctx.backend.take());
+        let next = self.upstreams.select_other(
+            &ctx.instance_id,
+            ctx.consumer.limiter_key().as_bytes(),
+            &ctx.failed_backends,
+        );
+        let Some(backend) = next else {
+            ctx.backend = ctx.failed_backends.pop();
+            return e;
+        };
+
+        ctx.instance = backend
+            .ext
+            .get::<UpstreamAddress>()
+            .map_or(backend.addr.to_string(), |u| u.0.clone());
+        ctx.backend = Some(backend);
+        ctx.retries += 1;
+        e.set_retry(true);
+        e
+    }
+
+    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, ctx: &mut Self::CTX) -> u16
+    where
+        Self::CTX: Send + Sync,
+    {
+        let code = match e.etype() {
+            ErrorType::HTTPStatus(code) => *code,
+            _ => match e.esource() {
+                ErrorSource::Upstream => {
+                    let (error, label) = match e.etype() {
+                        ErrorType::ConnectTimedout
+                        | ErrorType::ReadTimedout
+                        | ErrorType::WriteTimedout => (
+                            ProxyError::UpstreamTimeout(ctx.consumer.network.clone()),
+                            "timeout",
+                        ),
+                        ErrorType::ConnectRefused
+                        | ErrorType::ConnectNoRoute
+                        | ErrorType::ConnectError => (
+                            ProxyError::UpstreamError(ctx.consumer.network.clone()),
+                            "connect",
+                        ),
+                        _ => (
+                            ProxyError::UpstreamError(ctx.consumer.network.clone()),
+                            "response",
+                        ),
+                    };
+                    self.state
+                        .metrics
+                        .inc_upstream_error(&ctx.consumer, &ctx.instance, label);
+
+                    let code = error.status().as_u16();
+                    // Clients get the JSON envelope unless the upstream already started the
+                    // response.
+                    if session.response_written().is_none() {
+                        self.respond_error(session, ctx, error).await;
+                        return code;
+                    }
+                    code
+                }
+                ErrorSource::Downstream => match e.etype() {
+                    ErrorType::ReadError | ErrorType::WriteError | ErrorType::ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+        if code > 0 && session.response_written().is_none() {
+            let _ = session.respond_error(code).await;
+        }
+        code
+    }
+
     async fn logging(
         &self,
         session: &mut Session,
         _e: Option<&pingora::Error>,
         ctx: &mut Self::CTX,
     ) {
+        // Free the in-flight slot of the consumer as soon as the request is done.
+        drop(ctx.concurrency_permit.take());
+
         if !ctx.is_health_request {
             let response_code = session
                 .response_written()
                 .map_or(0, |resp| resp.status.as_u16());
 
+            // Coalesced-only routes have no cache rule, they would only inflate the misses.
+            match session.cache.phase() {
+                _ if ctx.coalesce_only => {}
+                CachePhase::Hit => self.state.metrics.inc_cache_hit(&ctx.consumer),
+                CachePhase::Miss | CachePhase::Expired => {
+                    self.state.metrics.inc_cache_miss(&ctx.consumer)
+                }
+                _ => {}
+            }
+
             self.state.metrics.inc_http_total_request(
                 &ctx.consumer,
                 &self.config.proxy_namespace,
                 &ctx.instance,
                 &response_code,
+                ctx.cost,
             );
 
             if let Some(start) = ctx.start_time {
@@ -253,12 +877,14 @@ impl ProxyHttp for KupoProxy {
                     dur,
                 );
                 info!(
+                    request_id = ctx.request_id,
                     response_time = dur.as_millis(),
                     "{} response code: {response_code}",
                     self.request_summary(session, ctx)
                 );
             } else {
                 info!(
+                    request_id = ctx.request_id,
                     "{} response code: {response_code}",
                     self.request_summary(session, ctx)
                 );
@@ -266,3 +892,124 @@ impl ProxyHttp for KupoProxy {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipnet::IpNet;
+
+    fn host_network(host: &str) -> Option<(String, String)> {
+        parse_host_network(&Regex::new(NETWORK_HOST_PATTERN).unwrap(), host)
+    }
+
+    fn network(network: &str, version: &str) -> Option<(String, String)> {
+        Some((network.to_string(), version.to_string()))
+    }
+
+    #[test]
+    fn legacy_hosts_have_no_network() {
+        assert_eq!(host_network("mainnet.kupo-m1.demeter.run"), None);
+        assert_eq!(
+            host_network("dmtr_kupo1abc.preprod.kupo-m1.demeter.run"),
+            None
+        );
+        assert_eq!(
+            host_network("kupo-proxy.ext-kupo-m1.svc.cluster.local"),
+            None
+        );
+    }
+
+    #[test]
+    fn network_host_is_parsed() {
+        assert_eq!(
+            host_network("cardano-mainnet-v2.kupo-m1.demeter.run"),
+            network("cardano-mainnet", "v2")
+        );
+        assert_eq!(
+            host_network("preprod-v1.kupo-m1.demeter.run"),
+            network("cardano-preprod", "v1")
+        );
+        assert_eq!(
+            host_network("Cardano-Mainnet-V2.kupo-m1.demeter.run"),
+            network("cardano-mainnet", "v2")
+        );
+    }
+
+    #[test]
+    fn key_prefixed_host_is_parsed() {
+        assert_eq!(
+            host_network("dmtr_kupo1abc.cardano-preview-v2.kupo-m1.demeter.run"),
+            network("cardano-preview", "v2")
+        );
+    }
+
+    #[test]
+    fn matching_host_network_is_allowed() {
+        let host = host_network("dmtr_kupo1abc.cardano-mainnet-v2.kupo-m1.demeter.run");
+        assert!(check_host_network(host, "cardano-mainnet", "v2").is_ok());
+        assert!(check_host_network(None, "cardano-mainnet", "v2").is_ok());
+    }
+
+    #[test]
+    fn other_network_or_version_is_misdirected() {
+        let host = host_network("dmtr_kupo1abc.cardano-preprod-v2.kupo-m1.demeter.run");
+        let error = check_host_network(host, "cardano-mainnet", "v2").unwrap_err();
+        assert_eq!(error.status(), StatusCode::MISDIRECTED_REQUEST);
+        assert_eq!(error.code(), "network_mismatch");
+
+        let host = host_network("cardano-mainnet-v1.kupo-m1.demeter.run");
+        let error = check_host_network(host, "cardano-mainnet", "v2").unwrap_err();
+        assert_eq!(error.status(), StatusCode::MISDIRECTED_REQUEST);
+    }
+
+    fn client_ip(peer: &str, hops: &[&str]) -> IpAddr {
+        let trusted: Vec<IpNet> = ["10.0.0.0/8", "fd00::/8"]
+            .iter()
+            .map(|net| net.parse().unwrap())
+            .collect();
+        forwarded_client_ip(peer.parse().unwrap(), hops, |ip| {
+            trusted.iter().any(|net| net.contains(&ip))
+        })
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let ip = client_ip("203.0.113.7", &["198.51.100.1"]);
+        assert_eq!(ip, "203.0.113.7".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn trusted_peer_forwards_the_closest_hop() {
+        let ip = client_ip("10.0.0.2", &["198.51.100.1"]);
+        assert_eq!(ip, "198.51.100.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn trusted_hops_are_walked_until_an_untrusted_one() {
+        let ip = client_ip(
+            "10.0.0.2",
+            &["192.0.2.9", " 198.51.100.1", " 10.1.2.3", " fd00::1"],
+        );
+        assert_eq!(ip, "198.51.100.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn spoofed_hops_before_an_untrusted_one_are_ignored() {
+        // The client prepended its own X-Forwarded-For value, only the hop added by the trusted
+        // proxy is believed.
+        let ip = client_ip("10.0.0.2", &["10.0.0.9", " 198.51.100.1"]);
+        assert_eq!(ip, "198.51.100.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn invalid_hop_stops_the_walk() {
+        let ip = client_ip("10.0.0.2", &["198.51.100.1", " unknown"]);
+        assert_eq!(ip, "10.0.0.2".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn only_trusted_hops_resolve_to_the_furthest_one() {
+        let ip = client_ip("10.0.0.2", &["10.0.0.9", " 10.0.0.5"]);
+        assert_eq!(ip, "10.0.0.9".parse::<IpAddr>().unwrap());
+    }
+}
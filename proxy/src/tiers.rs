@@ -8,7 +8,7 @@ use serde_json::Value;
 use tokio::runtime::{Handle, Runtime};
 use tracing::{error, info, warn};
 
-use crate::{config::Config, State, Tier};
+use crate::{config::Config, RouteCost, RouteTimeouts, State, Tier};
 
 pub struct TierBackgroundService {
     state: Arc<State>,
@@ -23,20 +23,46 @@ impl TierBackgroundService {
         let contents = fs::read_to_string(&self.config.proxy_tiers_path)?;
 
         let value: Value = toml::from_str(&contents)?;
-        let tiers_value: Option<&Value> = value.get("tiers");
-        if tiers_value.is_none() {
+
+        // Everything is parsed before being applied, so an invalid file keeps the previous
+        // configuration as a whole.
+        let costs = match value.get("costs") {
+            Some(costs) => serde_json::from_value::<Vec<RouteCost>>(costs.to_owned())?,
+            None => Vec::new(),
+        };
+
+        let timeouts = match value.get("timeouts") {
+            Some(timeouts) => serde_json::from_value::<Vec<RouteTimeouts>>(timeouts.to_owned())?,
+            None => Vec::new(),
+        };
+
+        let tiers = match value.get("tiers") {
+            Some(tiers) => Some(serde_json::from_value::<Vec<Tier>>(tiers.to_owned())?),
+            None => None,
+        };
+
+        let Some(tiers) = tiers else {
             warn!("tiers not configured on toml");
+            *self.state.costs.write().await = costs;
+            *self.state.timeouts.write().await = timeouts;
             return Ok(());
+        };
+
+        // Requests never see new costs or timeouts with the previous tiers.
+        {
+            let mut state_costs = self.state.costs.write().await;
+            let mut state_timeouts = self.state.timeouts.write().await;
+            let mut state_tiers = self.state.tiers.write().await;
+            *state_costs = costs;
+            *state_timeouts = timeouts;
+            *state_tiers = tiers
+                .into_iter()
+                .map(|tier| (tier.name.clone(), tier))
+                .collect();
         }
 
-        let tiers = serde_json::from_value::<Vec<Tier>>(tiers_value.unwrap().to_owned())?;
-
-        *self.state.tiers.write().await = tiers
-            .into_iter()
-            .map(|tier| (tier.name.clone(), tier))
-            .collect();
-
-        self.state.limiter.write().await.clear();
+        // Concurrency slots are resized to the new limits as requests come in.
+        self.state.limiter.clear().await;
 
         Ok(())
     }
@@ -92,7 +118,7 @@ impl BackgroundService for TierBackgroundService {
     }
 }
 
-fn runtime_handle() -> Handle {
+pub fn runtime_handle() -> Handle {
     match Handle::try_current() {
         Ok(h) => h,
         Err(_) => {
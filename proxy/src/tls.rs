@@ -0,0 +1,234 @@
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use std::{fs, path::Path};
+
+use async_trait::async_trait;
+use notify::{Event, PollWatcher, RecursiveMode, Watcher};
+use pingora::{
+    listeners::TlsAccept,
+    protocols::tls::TlsRef,
+    server::ShutdownWatch,
+    services::background::BackgroundService,
+    tls::{
+        asn1::Asn1Time,
+        error::ErrorStack,
+        ext,
+        pkey::{PKey, Private},
+        ssl::NameType,
+        x509::X509,
+    },
+};
+use tracing::{error, info};
+
+use crate::{
+    config::{Config, TlsCertificatePaths},
+    tiers::runtime_handle,
+    State,
+};
+
+/// Certificate and key served on the TLS listener, with the rest of the chain from the same file.
+pub struct CertifiedKey {
+    crt_path: String,
+    names: Vec<String>,
+    leaf: X509,
+    chain: Vec<X509>,
+    key: PKey<Private>,
+}
+impl CertifiedKey {
+    pub fn load(paths: &TlsCertificatePaths) -> Result<Self, Box<dyn Error>> {
+        let mut certs = X509::stack_from_pem(&fs::read(&paths.crt_path)?)?.into_iter();
+        let leaf = certs.next().ok_or("certificate file is empty")?;
+        let key = PKey::private_key_from_pem(&fs::read(&paths.key_path)?)?;
+
+        // Secrets are not updated atomically, the key may still belong to the previous cert.
+        if !leaf.public_key()?.public_eq(&key) {
+            return Err("private key doesn't match the certificate".into());
+        }
+
+        let names = leaf
+            .subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| name.dnsname())
+                    .map(|name| name.to_ascii_lowercase())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Self {
+            crt_path: paths.crt_path.clone(),
+            names,
+            leaf,
+            chain: certs.collect(),
+            key,
+        })
+    }
+
+    /// Whether a DNS name of the certificate covers the server name, a wildcard covers a single
+    /// label.
+    fn matches(&self, server_name: &str) -> bool {
+        self.names.iter().any(|name| match name.strip_prefix("*.") {
+            Some(suffix) => server_name
+                .split_once('.')
+                .is_some_and(|(_, parent)| parent == suffix),
+            None => name == server_name,
+        })
+    }
+
+    /// Unix timestamp of the certificate `notAfter`.
+    pub fn expires_at(&self) -> Result<i64, ErrorStack> {
+        let diff = Asn1Time::from_unix(0)?.diff(self.leaf.not_after())?;
+        Ok(diff.days as i64 * 86400 + diff.secs as i64)
+    }
+
+    fn apply(&self, ssl: &mut TlsRef) -> Result<(), ErrorStack> {
+        ext::ssl_use_certificate(ssl, &self.leaf)?;
+        ext::ssl_use_private_key(ssl, &self.key)?;
+        for cert in &self.chain {
+            ext::ssl_add_chain_cert(ssl, cert)?;
+        }
+        Ok(())
+    }
+}
+
+/// Certificates picked by SNI on every handshake, so a reload applies to new connections only.
+/// The first one is served when no other matches the server name.
+#[derive(Clone)]
+pub struct TlsCertificates {
+    current: Arc<RwLock<Vec<Arc<CertifiedKey>>>>,
+}
+impl TlsCertificates {
+    pub fn load(config: &Config) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            current: Arc::new(RwLock::new(load_certificates(config)?)),
+        })
+    }
+
+    fn current(&self) -> Vec<Arc<CertifiedKey>> {
+        self.current.read().unwrap().clone()
+    }
+
+    fn select(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.current.read().unwrap();
+        let server_name = server_name.map(|name| name.to_ascii_lowercase());
+        server_name
+            .and_then(|name| certs.iter().find(|cert| cert.matches(&name)))
+            .or(certs.first())
+            .cloned()
+    }
+
+    fn replace(&self, certs: Vec<Arc<CertifiedKey>>) {
+        *self.current.write().unwrap() = certs;
+    }
+}
+
+fn load_certificates(config: &Config) -> Result<Vec<Arc<CertifiedKey>>, Box<dyn Error>> {
+    config
+        .tls_certificate_paths()
+        .iter()
+        .map(|paths| CertifiedKey::load(paths).map(Arc::new))
+        .collect()
+}
+
+#[async_trait]
+impl TlsAccept for TlsCertificates {
+    async fn certificate_callback(&self, ssl: &mut TlsRef) {
+        let Some(cert) = self.select(ssl.servername(NameType::HOST_NAME)) else {
+            return;
+        };
+        if let Err(err) = cert.apply(ssl) {
+            error!(error = err.to_string(), "error to set tls certificate");
+        }
+    }
+}
+
+pub struct TlsBackgroundService {
+    state: Arc<State>,
+    config: Arc<Config>,
+    certificates: TlsCertificates,
+}
+impl TlsBackgroundService {
+    pub fn new(state: Arc<State>, config: Arc<Config>, certificates: TlsCertificates) -> Self {
+        Self {
+            state,
+            config,
+            certificates,
+        }
+    }
+
+    fn update_expiry(&self) {
+        for cert in self.certificates.current() {
+            match cert.expires_at() {
+                Ok(expires_at) => self
+                    .state
+                    .metrics
+                    .set_tls_cert_expiry(&cert.crt_path, expires_at),
+                Err(err) => error!(
+                    error = err.to_string(),
+                    "error to read tls certificate expiry"
+                ),
+            }
+        }
+    }
+
+    /// Certificates are replaced together, so a single invalid pair keeps the previous set.
+    fn update_certificates(&self) -> Result<(), Box<dyn Error>> {
+        let certs = load_certificates(&self.config)?;
+        self.certificates.replace(certs);
+        self.update_expiry();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BackgroundService for TlsBackgroundService {
+    async fn start(&self, mut _shutdown: ShutdownWatch) {
+        self.update_expiry();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<Event>(1);
+
+        let watcher_config = notify::Config::default()
+            .with_compare_contents(true)
+            .with_poll_interval(self.config.ssl_poll_interval);
+
+        let watcher_result = PollWatcher::new(
+            move |res| {
+                if let Ok(event) = res {
+                    runtime_handle()
+                        .block_on(async { tx.send(event).await })
+                        .unwrap();
+                }
+            },
+            watcher_config,
+        );
+        if let Err(err) = watcher_result {
+            error!(error = err.to_string(), "error to watcher tls certificate");
+            return;
+        }
+
+        let mut watcher = watcher_result.unwrap();
+        for paths in self.config.tls_certificate_paths() {
+            for path in [&paths.crt_path, &paths.key_path] {
+                let watcher_result = watcher.watch(Path::new(path), RecursiveMode::NonRecursive);
+                if let Err(err) = watcher_result {
+                    error!(error = err.to_string(), "error to watcher tls certificate");
+                    return;
+                }
+            }
+        }
+
+        loop {
+            let result = rx.recv().await;
+            if result.is_some() {
+                // The previous certificate keeps being served until a valid pair is read.
+                if let Err(err) = self.update_certificates() {
+                    error!(error = err.to_string(), "error to update tls certificate");
+                    continue;
+                }
+
+                info!("tls certificate modified");
+            }
+        }
+    }
+}
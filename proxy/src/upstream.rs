@@ -0,0 +1,115 @@
+use async_trait::async_trait;
+use pingora::{
+    http::Uri,
+    lb::{
+        discovery::ServiceDiscovery, health_check::HttpHealthCheck, selection::RoundRobin, Backend,
+        Backends, LoadBalancer,
+    },
+    Error, ErrorType, Result,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+};
+use tracing::warn;
+
+use crate::config::{Config, KupoInstance, KupoUpstream};
+
+/// Configured address of the upstream a backend was resolved from, stored in the backend
+/// extensions so metrics keep the k8s service name instead of the pod address.
+#[derive(Debug, Clone)]
+pub struct UpstreamAddress(pub String);
+
+/// Resolves every upstream address on each discovery round, so a headless service returns one
+/// backend per Kupo replica and restarted pods are picked up with their new addresses.
+pub struct DnsDiscovery {
+    upstreams: Vec<KupoUpstream>,
+}
+impl DnsDiscovery {
+    pub fn new(upstreams: Vec<KupoUpstream>) -> Self {
+        Self { upstreams }
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for DnsDiscovery {
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let mut backends = BTreeSet::new();
+
+        for upstream in self.upstreams.iter() {
+            let addrs = match tokio::net::lookup_host(&upstream.address).await {
+                Ok(addrs) => addrs,
+                Err(err) => {
+                    warn!(
+                        error = err.to_string(),
+                        upstream = upstream.address,
+                        "Failed to resolve upstream"
+                    );
+                    continue;
+                }
+            };
+
+            for addr in addrs {
+                let mut backend = Backend::new(&addr.to_string())?;
+                backend.weight = upstream.weight;
+                backend
+                    .ext
+                    .insert(UpstreamAddress(upstream.address.clone()));
+                backends.insert(backend);
+            }
+        }
+
+        // Keep the previous backends when nothing resolves instead of emptying the pool.
+        if backends.is_empty() {
+            return Error::e_explain(ErrorType::ConnectError, "no upstream could be resolved");
+        }
+
+        Ok((backends, HashMap::new()))
+    }
+}
+
+pub type KupoLoadBalancer = LoadBalancer<RoundRobin>;
+
+/// Builds a weighted round-robin balancer for a routing entry. Backends failing the Kupo
+/// `/health` check are taken out of rotation until they recover.
+pub fn build_load_balancer(instance: &KupoInstance, config: &Config) -> KupoLoadBalancer {
+    let discovery = DnsDiscovery::new(instance.upstreams());
+    let mut load_balancer = LoadBalancer::from_backends(Backends::new(Box::new(discovery)));
+
+    let mut health_check = HttpHealthCheck::new(&instance.network, false);
+    health_check.req.set_uri(Uri::from_static("/health"));
+    load_balancer.set_health_check(Box::new(health_check));
+    load_balancer.health_check_frequency = Some(config.upstream_health_check_interval);
+    load_balancer.update_frequency = Some(config.upstream_discovery_interval);
+
+    load_balancer
+}
+
+/// Load balancers of every routing entry, indexed by `KupoInstance::id`.
+#[derive(Default)]
+pub struct Upstreams {
+    balancers: HashMap<String, Arc<KupoLoadBalancer>>,
+}
+impl Upstreams {
+    pub fn insert(&mut self, instance: &KupoInstance, balancer: Arc<KupoLoadBalancer>) {
+        self.balancers.insert(instance.id(), balancer);
+    }
+
+    pub fn select(&self, instance: &KupoInstance, key: &[u8]) -> Option<Backend> {
+        self.balancers.get(&instance.id())?.select(key, 256)
+    }
+
+    /// Healthy upstream of the instance other than the ones that already failed the request.
+    pub fn select_other(
+        &self,
+        instance_id: &str,
+        key: &[u8],
+        failed: &[Backend],
+    ) -> Option<Backend> {
+        self.balancers
+            .get(instance_id)?
+            .select_with(key, 256, |backend, healthy| {
+                healthy && !failed.contains(backend)
+            })
+    }
+}